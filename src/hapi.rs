@@ -1,59 +1,207 @@
 mod archive;
+mod error;
+mod names;
+mod raw;
 mod reader;
+#[cfg(test)]
+mod test_support;
 
 pub use self::archive::*;
+pub use self::error::*;
+pub use self::names::*;
+pub use self::raw::*;
 use self::reader::*;
 
 // =^w^= =^w^= =^w^= =^w^= =^w^=
 // ~* common data structures *~
 // =^w^= =^w^= =^w^= =^w^= =^w^=
 
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
-use binrw::{binread, prelude::*, FilePtr32, NullString, ReadOptions};
+use binrw::{binread, helpers, prelude::*, FilePtr32, NullString, ReadOptions};
 use std::io::{Read, Seek, SeekFrom};
 
 const _HAPI_MAGIC: &[u8] = b"HAPI";
 const HAPI_SAVE_MARKER: &[u8] = b"BANK";
 const HAPI_ARCHIVE_MARKER: &[u8] = &[0x00, 0x00, 0x01, 0x00];
 const HAPI_CHUNK_SIZE: u32 = 65536;
+const HAPI_HEADER_SIZE: u64 = 20;
 
 // HAPI header structure: 20 bytes (including magic)
-#[derive(Debug, BinRead, Clone)]
+/// The header at the start of every HAPI file, as returned by [`parse_raw`].
+#[binread]
+#[derive(Debug, Clone)]
 #[br(little, magic = b"HAPI")]
-struct HapiHeader {
-	marker: [u8; 4], // HAPI_SAVE_MARKER or HAPI_ARCHIVE_MARKER
-	toc_size: u32,   // size of table of contents
-	#[br(map = |key: u32| if key == 0 { None } else { Some( !((key * 4) | (key >> 6)) ) })]
-	key: Option<u32>, // XOR cipher key
-	toc_offset: u32, // root directory of archive
+pub struct HapiHeader {
+	/// What kind of HAPI file this is: `00 00 01 00` for archives, or `BANK` for
+	/// save data.
+	pub marker: [u8; 4],
+	/// Where the table of contents ends, as an offset from the start of the file.
+	///
+	/// Despite the name, this counts everything before [`toc_offset`](Self::toc_offset)
+	/// too, which is normally just the 20-byte header. The table of contents itself
+	/// takes up `toc_size - toc_offset` bytes.
+	pub toc_size: u32,
+	/// The key the file is enciphered with, already decoded (see [`decode_key`]), or
+	/// `None` if it isn't enciphered.
+	#[br(map = |key: u32| if key == 0 { None } else { Some(decode_key(key)) })]
+	pub key: Option<u32>,
+	/// Where the root directory starts, which is also where the cipher starts.
+	pub toc_offset: u32,
+}
+
+/// Turns the cipher key as stored in an archive's header into the key used to
+/// decipher it.
+///
+/// A stored key of `0` means the archive isn't enciphered at all, so it has no
+/// meaningful decoded form.
+///
+/// The transform (`!((stored << 2) | (stored >> 6))`) loses information: bits shifted
+/// out the top are gone, and the two shifted copies are OR'd together. That doesn't
+/// matter in practice, since the cipher only ever uses the low 8 bits of the key;
+/// see [`encode_key`] for the other direction.
+pub fn decode_key(stored: u32) -> u32 {
+	!((stored << 2) | (stored >> 6))
+}
+
+/// Finds a value to store in an archive's header so that it deciphers with `key`.
+///
+/// This is the inverse of [`decode_key`] as far as the cipher is concerned: the result
+/// decodes to a key with the same low 8 bits as `key`, which are the only ones the
+/// cipher looks at. The upper bits generally won't survive the round trip. The result
+/// is never `0`, since that would mark the archive as not enciphered.
+///
+/// # Examples
+/// ```
+/// use hapi::{decode_key, encode_key};
+///
+/// for key in 0..=255 {
+/// 	let stored = encode_key(key);
+/// 	assert_ne!(stored, 0);
+/// 	assert_eq!(decode_key(stored) & 0xff, key);
+/// }
+/// // Only the low byte is kept
+/// assert_eq!(decode_key(encode_key(0x1234_5678)) & 0xff, 0x78);
+/// ```
+pub fn encode_key(key: u32) -> u32 {
+	// For stored values under 0x100, the low byte of the decoded key is just the
+	// stored byte rotated left by 2 and inverted, so undo that
+	let stored = (!key as u8).rotate_right(2) as u32;
+
+	if stored != 0 {
+		stored
+	} else {
+		// A key ending in 0xff would need a stored 0, which means "no key". 0x4000
+		// decodes to 0xfffefeff instead, which has the same low byte.
+		0x4000
+	}
 }
 
 // Directory: array of indexes to Entries
 /// A directory within a [`HapiArchive`].
 #[binread]
 #[derive(Debug, Clone)]
-#[br(little, import(path: PathBuf))]
+#[br(little, import(
+	path: PathBuf,
+	lazy: bool,
+	names: NameEncoding,
+	toc_position: Option<u32>,
+	depth: ParseDepth,
+))]
 pub struct HapiDirectory {
 	#[br(calc = path)]
 	path: PathBuf,
+	#[br(parse_with = stream_offset)]
+	offset: u32,
+	#[br(calc = toc_position)]
+	toc_position: Option<u32>,
+	// the flag byte of the index entry naming this directory, set by whoever parsed that
+	#[br(default)]
+	flags: u8,
+	#[br(calc = true)]
+	loaded: bool,
 	#[br(temp)]
 	count: u32,
-	#[br(parse_with = FilePtr32::parse, args { count: count as usize, inner: (path.clone(),) })]
+	// Empty directories (like the root of a placeholder archive) often don't point
+	// anywhere meaningful, so don't go looking
+	#[br(
+		if(count > 0),
+		parse_with = parse_entries,
+		args(count, (path.clone(), lazy, names, depth.child())),
+	)]
 	contents: Vec<HapiEntry>,
+	#[br(calc = contents.iter().map(HapiEntry::total_size).sum())]
+	total_size: u64,
+	#[br(calc = contents.iter().map(|entry| entry.counts().0).sum())]
+	file_count: usize,
+	#[br(calc = contents.iter().map(|entry| entry.counts().1).sum())]
+	dir_count: usize,
+}
+
+impl HapiDirectory {
+	// A directory whose contents haven't been parsed yet
+	fn unloaded(path: PathBuf, offset: u32, toc_position: Option<u32>, flags: u8) -> Self {
+		HapiDirectory {
+			path,
+			offset,
+			toc_position,
+			flags,
+			loaded: false,
+			contents: Vec::new(),
+			total_size: 0,
+			file_count: 0,
+			dir_count: 0,
+		}
+	}
+}
+
+// Reads `count` entries from wherever the next offset points. The count can be garbage
+// (say, when the archive is deciphered with the wrong key), so the entries are collected
+// as they're read rather than making room for all of them up front.
+fn parse_entries<R: Read + Seek>(
+	reader: &mut R,
+	options: &ReadOptions,
+	(count, args): (u32, <HapiEntry as BinRead>::Args),
+) -> BinResult<Vec<HapiEntry>> {
+	FilePtr32::parse_with(helpers::count(count as usize))(reader, options, args)
+}
+
+// Records where in the stream a structure starts, without reading anything
+fn stream_offset<R: Read + Seek>(reader: &mut R, _: &ReadOptions, _: ()) -> BinResult<u32> {
+	offset_u32(reader.stream_position()?)
+}
+
+// Offsets within an archive are stored as 32 bits, so anything past the first 4 GiB of a
+// bigger stream can't be pointed to. Rather than let a position out there wrap around to
+// somewhere near the start, refuse it.
+pub(crate) fn offset_u32(pos: u64) -> BinResult<u32> {
+	u32::try_from(pos).map_err(|_| binrw::Error::AssertFail {
+		pos,
+		message: format!("offset {pos} is past the 4 GiB that HAPI archives can address"),
+	})
 }
 
 // Index: names entry, points to either file or directory data
+/// An entry in a directory's list of entries, naming a file or directory and pointing
+/// to its record, as returned by [`parse_raw`].
 #[derive(Debug, BinRead, Clone)]
 #[br(little)]
-struct HapiEntryIndex {
+pub struct HapiEntryIndex {
 	#[br(parse_with = FilePtr32::parse)]
 	name: NullString,
-	entry_offset: u32,
-	#[br(map = |flag: u8| flag == 1)]
-	is_dir: bool,
+	/// Where the entry's record is within the archive.
+	pub entry_offset: u32,
+	/// The entry's flag byte, as stored. Only bit 0 (set for directories) has a known
+	/// meaning; some variants of the format set others.
+	pub flags: u8,
+	/// Whether the entry is a directory.
+	#[br(calc = flags & HAPI_FLAG_DIR != 0)]
+	pub is_dir: bool,
 }
 
+// The only bit of an entry's flags that's understood
+const HAPI_FLAG_DIR: u8 = 1;
+
 // Entry: either file or directory
 /// An entry within a [`HapiArchive`]: either a file or a directory.
 #[derive(Debug, Clone)]
@@ -62,27 +210,95 @@ pub enum HapiEntry {
 	Directory(HapiDirectory),
 }
 
+/// How deeply nested the entries being parsed are, and how deep they may go (see
+/// [`OpenOptions::max_depth`]), as passed along when parsing directories.
+///
+/// This only shows up in the arguments for parsing [`HapiDirectory`] and [`HapiEntry`]
+/// with `binrw` directly; [`HapiArchive`] takes care of it otherwise. The default is
+/// right for the root directory, with no limit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ParseDepth {
+	// the root directory's entries are at 1
+	depth: usize,
+	limit: Option<usize>,
+}
+
+impl ParseDepth {
+	// The depth of a directory at `path`, from the archive root
+	pub(crate) fn of(path: &Path, limit: Option<usize>) -> Self {
+		ParseDepth {
+			depth: path
+				.components()
+				.filter(|c| matches!(c, Component::Normal(_)))
+				.count(),
+			limit,
+		}
+	}
+
+	fn child(self) -> Self {
+		ParseDepth {
+			depth: self.depth + 1,
+			..self
+		}
+	}
+}
+
 impl BinRead for HapiEntry {
-	type Args = (PathBuf,);
+	type Args = (PathBuf, bool, NameEncoding, ParseDepth);
 
 	fn read_options<R: Read + Seek>(
 		reader: &mut R,
 		options: &ReadOptions,
 		args: Self::Args,
 	) -> BinResult<Self> {
+		let toc_position = offset_u32(reader.stream_position()?)?;
 		let index = HapiEntryIndex::read_options(reader, options, ())?;
 
-		let mut path = args.0;
+		let (mut path, lazy, names, depth) = args;
+		if let Some(limit) = depth.limit.filter(|&limit| depth.depth > limit) {
+			return Err(binrw::Error::Custom {
+				pos: toc_position as u64,
+				err: Box::new(TooDeep { path, limit }),
+			});
+		}
+		let name = names
+			.try_decode(&index.name)
+			.ok_or_else(|| binrw::Error::Custom {
+				pos: toc_position as u64,
+				err: Box::new(InvalidUtf8Name {
+					raw_bytes: index.name.0.clone(),
+					entry_offset: index.entry_offset,
+					parent: path.clone(),
+				}),
+			})?;
 		// FIXME this will MISBEHAVE if `name` is empty or weird (e.g. "..")
-		path.push(index.name.into_string());
+		path.push(&*name);
+
+		// Subdirectories are left for later when parsing lazily
+		if index.is_dir && lazy {
+			return Ok(HapiEntry::Directory(HapiDirectory::unloaded(
+				path,
+				index.entry_offset,
+				Some(toc_position),
+				index.flags,
+			)));
+		}
 
 		let old_pos = SeekFrom::Start(reader.stream_position()?);
 		reader.seek(SeekFrom::Start(index.entry_offset as u64))?;
 
 		let entry = if index.is_dir {
-			HapiEntry::Directory(HapiDirectory::read_options(reader, options, (path,))?)
+			let mut dir = HapiDirectory::read_options(
+				reader,
+				options,
+				(path, false, names, Some(toc_position), depth),
+			)?;
+			dir.flags = index.flags;
+			HapiEntry::Directory(dir)
 		} else {
-			HapiEntry::File(HapiFile::read_options(reader, options, (path,))?)
+			let mut file = HapiFile::read_options(reader, options, (path, toc_position))?;
+			file.flags = index.flags;
+			HapiEntry::File(file)
 		};
 
 		reader.seek(old_pos)?;
@@ -95,10 +311,17 @@ impl BinRead for HapiEntry {
 // Uncompressed case: points to contiguous file data
 /// A file within a [`HapiArchive`].
 #[derive(Debug, BinRead, Clone)]
-#[br(little, import(path: PathBuf))]
+#[br(little, import(path: PathBuf, toc_position: u32))]
 pub struct HapiFile {
 	#[br(calc = path)]
 	path: PathBuf,
+	#[br(parse_with = stream_offset)]
+	offset: u32,
+	#[br(calc = toc_position)]
+	toc_position: u32,
+	// the flag byte of the index entry naming this file, set by whoever parsed that
+	#[br(default)]
+	flags: u8,
 	/// Where the file starts within the archive. (The contents at this location
 	/// depend on if it's compressed or not.)
 	pub contents_offset: u32,
@@ -118,25 +341,80 @@ pub enum HapiCompressionType {
 	Zlib,
 }
 
+impl HapiCompressionType {
+	/// Guesses how `data` is best stored in an archive.
+	///
+	/// Data too small to be worth the chunk headers, or that looks already compressed
+	/// (nearly 8 bits of entropy per byte, like most audio and image formats), gets
+	/// [`None`](Self::None); everything else gets [`Zlib`](Self::Zlib), which generally
+	/// compresses better than [`Lz77`](Self::Lz77). Only a sample of the data from its
+	/// start, middle and end is looked at, so this is cheap even for big files.
+	pub fn recommended_for(data: &[u8]) -> HapiCompressionType {
+		// A chunk header alone is 19 bytes, plus 4 for its entry in the size table
+		const MIN_SIZE: usize = 64;
+		const SAMPLE_SIZE: usize = 4096;
+		const MAX_ENTROPY: f64 = 7.5;
+
+		if data.len() < MIN_SIZE {
+			return HapiCompressionType::None;
+		}
+
+		let samples = if data.len() <= SAMPLE_SIZE * 3 {
+			vec![data]
+		} else {
+			let middle = (data.len() - SAMPLE_SIZE) / 2;
+			vec![
+				&data[..SAMPLE_SIZE],
+				&data[middle..middle + SAMPLE_SIZE],
+				&data[data.len() - SAMPLE_SIZE..],
+			]
+		};
+
+		let mut counts = [0u32; 256];
+		let mut total = 0;
+		for &byte in samples.into_iter().flatten() {
+			counts[byte as usize] += 1;
+			total += 1;
+		}
+
+		let entropy: f64 = counts
+			.iter()
+			.filter(|&&count| count > 0)
+			.map(|&count| {
+				let p = count as f64 / total as f64;
+				-p * p.log2()
+			})
+			.sum();
+
+		// a small sample can't show as much entropy as the data really has
+		if entropy > MAX_ENTROPY.min((total as f64).log2() - 0.5) {
+			HapiCompressionType::None
+		} else {
+			HapiCompressionType::Zlib
+		}
+	}
+}
+
 // The target of a File entry: either uncompressed data, or a series of compressed chunks
 #[binread]
 #[derive(Debug)]
-#[br(little, import(extracted_size: u32, compression: HapiCompressionType))]
+#[br(little, import(extracted_size: u32, compression: HapiCompressionType, verify: bool))]
 enum HapiFileContents {
+	// The data itself is copied straight out of the archive instead, a piece at a time
 	#[br(pre_assert(compression == HapiCompressionType::None))]
-	Uncompressed(#[br(count = extracted_size)] Vec<u8>),
+	Uncompressed,
 	#[br(pre_assert(compression != HapiCompressionType::None))]
 	Compressed(
-		#[br(temp, calc = (extracted_size + HAPI_CHUNK_SIZE - 1) / HAPI_CHUNK_SIZE)] u32, // number of chunks
+		#[br(temp, calc = extracted_size.div_ceil(HAPI_CHUNK_SIZE))] u32, // number of chunks
 		#[br(temp, count = self_0)] Vec<u32>, // size of each chunk (unnecessary here)
-		#[br(count = self_0)] Vec<HapiCompressedChunk>, // the chunks themselves
+		#[br(args { count: self_0 as usize, inner: (verify,) })] Vec<HapiCompressedChunk>, // the chunks themselves
 	),
 }
 
 // Header preceding a chunk of compressed data
 #[binread]
 #[derive(Debug)]
-#[br(little, magic = b"SQSH")]
+#[br(little, magic = b"SQSH", import(verify: bool))]
 struct HapiCompressedChunk {
 	#[br(temp)]
 	_version_maybe: u8,
@@ -144,17 +422,204 @@ struct HapiCompressedChunk {
 	compression: HapiCompressionType,
 	#[br(map = |flag: u8| flag == 1)]
 	is_enciphered: bool,
+	#[br(temp)]
 	compressed_size: u32,
 	decompressed_size: u32,
 	checksum: u32,
 	#[br(
-		count = compressed_size,
+		parse_with = read_chunk_data,
+		args(compressed_size),
 		assert(
-			data.iter().fold(0, |c: u32, i: &u8| c.wrapping_add(*i as u32)) == checksum,
+			!verify || chunk_checksum(&data) == checksum,
 			"Chunk had bad checksum (expected {:x}, actual was {:x})",
 			checksum,
-			data.iter().fold(0, |c: u32, i: &u8| c.wrapping_add(*i as u32))
+			chunk_checksum(&data)
 		)
 	)]
 	data: Vec<u8>,
 }
+
+// Reads `size` bytes of chunk data, failing up front if the stream doesn't have that
+// many left, since a bogus size could otherwise mean a huge allocation
+fn read_chunk_data<R: Read + Seek>(
+	reader: &mut R,
+	_: &ReadOptions,
+	(size,): (u32,),
+) -> BinResult<Vec<u8>> {
+	let pos = reader.stream_position()?;
+	let available = reader.seek(SeekFrom::End(0))?.saturating_sub(pos);
+	reader.seek(SeekFrom::Start(pos))?;
+
+	if size as u64 > available {
+		return Err(binrw::Error::Custom {
+			pos,
+			err: Box::new(ChunkTooLarge {
+				declared: size,
+				available,
+			}),
+		});
+	}
+
+	let mut data = vec![0; size as usize];
+	reader.read_exact(&mut data)?;
+	Ok(data)
+}
+
+// The reason `read_chunk_data` failed, turned into `HapiError::ChunkTooLarge` later
+#[derive(Debug)]
+struct ChunkTooLarge {
+	declared: u32,
+	available: u64,
+}
+
+impl std::fmt::Display for ChunkTooLarge {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"chunk claims {} bytes of data, but only {} are left",
+			self.declared, self.available
+		)
+	}
+}
+
+// A name that `NameEncoding::Utf8Strict` rejected, turned into `HapiError::InvalidUtf8Name`
+// later
+#[derive(Debug)]
+struct InvalidUtf8Name {
+	raw_bytes: Vec<u8>,
+	entry_offset: u32,
+	parent: PathBuf,
+}
+
+impl std::fmt::Display for InvalidUtf8Name {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"name of entry at {:#x} in {} isn't valid UTF-8 (\"{}\")",
+			self.entry_offset,
+			self.parent.display(),
+			self.raw_bytes.escape_ascii()
+		)
+	}
+}
+
+// A directory whose entries are nested past the limit, turned into `HapiError::TooDeep`
+// later
+#[derive(Debug)]
+struct TooDeep {
+	path: PathBuf,
+	limit: usize,
+}
+
+impl std::fmt::Display for TooDeep {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"entries of {} are nested more than {} levels deep",
+			self.path.display(),
+			self.limit
+		)
+	}
+}
+
+// Checksum of a chunk's data as stored (i.e. before the chunk's own cipher is undone):
+// just the sum of every byte
+fn chunk_checksum(data: &[u8]) -> u32 {
+	data.iter()
+		.fold(0, |c: u32, i: &u8| c.wrapping_add(*i as u32))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	#[test]
+	fn strict_names_report_where_they_are() {
+		let data = Fixture::new(vec![dir(
+			"units",
+			vec![file("ok.fbi", ""), file(&b"bad\xff.fbi"[..], "")],
+		)])
+		.build();
+		assert!(HapiArchive::open_slice(&data).is_ok());
+
+		let options = OpenOptions::new().name_encoding(NameEncoding::Utf8Strict);
+		let err = HapiArchive::open_with(std::io::Cursor::new(&data), options).unwrap_err();
+		let HapiError::InvalidUtf8Name {
+			ref raw_bytes,
+			ref parent,
+			..
+		} = err
+		else {
+			panic!("expected InvalidUtf8Name, got {err:?}");
+		};
+		assert_eq!(raw_bytes, b"bad\xff.fbi");
+		assert_eq!(parent, Path::new("./units"));
+		assert!(err.to_string().contains(" in ./units "), "{err}");
+	}
+
+	#[test]
+	fn encoded_keys_decode_to_the_same_cipher() {
+		let plain: Vec<u8> = (0..=255).collect();
+		for key in (0..=0xff).chain([0x100, 0x1ff, 0xdead_beef, u32::MAX]) {
+			let stored = encode_key(key);
+			assert_ne!(stored, 0, "key {key:#x} encoded as 'no key'");
+			assert_eq!(decode_key(stored) & 0xff, key & 0xff);
+
+			let mut by_key = plain.clone();
+			let mut by_decoded = plain.clone();
+			cipher(Some(key), 0, 0, &mut by_key);
+			cipher(Some(decode_key(stored)), 0, 0, &mut by_decoded);
+			assert_eq!(by_key, by_decoded);
+		}
+	}
+
+	#[test]
+	fn recommended_compression() {
+		// xorshift, which is plenty random enough to look already compressed
+		let mut state = 0x2545_f491_u32;
+		let random: Vec<u8> = (0..100_000)
+			.map(|_| {
+				state ^= state << 13;
+				state ^= state >> 17;
+				state ^= state << 5;
+				state as u8
+			})
+			.collect();
+		assert_eq!(
+			HapiCompressionType::recommended_for(&random),
+			HapiCompressionType::None
+		);
+		assert_eq!(
+			HapiCompressionType::recommended_for(&random[..1000]),
+			HapiCompressionType::None
+		);
+
+		let repetitive = "[UNITINFO]\n{\n\tName=Commander;\n}\n".repeat(1000);
+		assert_eq!(
+			HapiCompressionType::recommended_for(repetitive.as_bytes()),
+			HapiCompressionType::Zlib
+		);
+		assert_eq!(
+			HapiCompressionType::recommended_for(&[0; 100]),
+			HapiCompressionType::Zlib
+		);
+		// too small to be worth it, however repetitive
+		assert_eq!(
+			HapiCompressionType::recommended_for(&[0; 10]),
+			HapiCompressionType::None
+		);
+	}
+
+	#[test]
+	fn decode_key_matches_header_transform() {
+		assert_eq!(decode_key(1), !4);
+		assert_eq!(decode_key(0x40), !0x101);
+		assert_eq!(decode_key(0x4000), 0xfffe_feff);
+		// the high bits shifted out are lost, so different stored keys can decode alike
+		assert_eq!(
+			decode_key(0x4000_0000) & 0xff,
+			decode_key(0x8000_0000) & 0xff
+		);
+	}
+}
@@ -1,48 +1,92 @@
+mod chunks;
 mod file_decoder;
+mod file_reader;
+#[cfg(feature = "game-aware")]
+mod game;
+#[cfg(feature = "gzip")]
+mod gzip;
+mod manifest;
+mod multipart;
+mod options;
+mod pool;
+mod repack;
+mod sink;
+mod sync;
+mod toc;
+mod verify;
+mod vfs;
+mod zlib;
+
+pub use self::chunks::*;
+pub use self::file_reader::*;
+pub use self::manifest::Manifest;
+pub use self::multipart::*;
+pub use self::options::*;
+pub use self::pool::*;
+pub use self::sink::*;
+pub use self::sync::*;
+pub use self::verify::{ChunkVerifyResult, FileVerifyResult, TreeAnomaly};
+pub use self::vfs::*;
+pub use self::zlib::*;
+
+use self::file_decoder::{is_output_limit_error, LimitedWriter};
+use self::manifest::{CrcWriter, ManifestEntry};
+pub(super) use self::toc::{toc_is_compressed, CompressedToc};
 
 use super::*;
 
-use std::cell::RefCell;
-use std::error::Error;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fs::{self, File};
 use std::io::{self, prelude::*};
-use std::path::{Path, PathBuf};
+use std::mem;
+use std::ops::Index;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
 
 use binrw::BinRead;
 
 /// An existing HAPI archive.
 ///
+/// An archive can be shared between threads as long as its stream can be sent between
+/// them. Reads from the stream take turns, but decompression doesn't, so extracting
+/// different files from several threads at once does speed things up.
+///
 /// # Examples
-/// ```
+/// ```no_run
 /// use hapi::prelude::*;
-/// use std::fs::{self, File};
+/// use std::fs::File;
 /// use std::io;
 ///
 /// // Open an existing archive file
 /// let file = File::open("Example.ufo")?;
 /// // Parse the archive, and get it ready for extraction
 /// let archive = HapiArchive::open(file)?;
-/// // Make a subdirectory to extract the contents into
-/// fs::create_dir("Example")?;
-/// // Extract the archive to the new subdirectory
+/// // Extract the archive to a new subdirectory
 /// archive.extract_all("Example")?;
 /// // If the archive contains a "Copyright.txt" at its root, display it on stdout
-/// let copyright = archive.iter().find_map(|x| {
+/// let copyright = archive.contents().find_map(|x| {
 /// 	if let HapiEntry::File(f) = x {
 /// 		if f.name().eq_ignore_ascii_case("Copyright.txt") {
-/// 			Some(f)
+/// 			return Some(f);
 /// 		}
 /// 	}
 /// 	None
 /// });
 /// if let Some(text) = copyright {
-/// 	archive.write_file(text, &mut io::stdout())?
+/// 	archive.write_file(text, &mut io::stdout())?;
 /// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
 /// ```
 #[derive(Debug)]
 pub struct HapiArchive<R: Read + Seek> {
-	reader: RefCell<HapiReader<R>>,
+	reader: Mutex<HapiReader<R>>,
+	zlib: Arc<dyn ZlibBackend>,
+	pool: Arc<dyn BufferPool>,
+	names: NameEncoding,
+	max_depth: Option<usize>,
+	warnings: Vec<HapiWarning>,
 	/// The root directory as a `HapiDirectory`, for completeness. Usually you'll
 	/// just want [`contents`](Self::contents), which is a shortcut for [`root_dir.iter`].
 	///
@@ -50,6 +94,48 @@ pub struct HapiArchive<R: Read + Seek> {
 	pub root_dir: HapiDirectory,
 }
 
+/// What kind of HAPI file a stream holds, from [`HapiArchive::probe_kind`] or
+/// [`HapiHeader::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HapiKind {
+	/// An archive, which can be opened.
+	Archive,
+	/// Saved game data (marked `BANK`), which can't be opened yet.
+	Save,
+	/// Something with a marker that isn't known, given here. It might still open as an
+	/// archive; see [`OpenOptions::on_unknown_marker`].
+	Unknown([u8; 4]),
+}
+
+impl HapiHeader {
+	/// Returns what kind of HAPI file this header belongs to, going by its
+	/// [`marker`](Self::marker).
+	pub fn kind(&self) -> HapiKind {
+		if self.marker == HAPI_ARCHIVE_MARKER {
+			HapiKind::Archive
+		} else if self.marker == HAPI_SAVE_MARKER {
+			HapiKind::Save
+		} else {
+			HapiKind::Unknown(self.marker)
+		}
+	}
+}
+
+/// How long opening an archive took, from [`HapiArchive::open_timed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenTiming {
+	/// Microseconds spent reading and checking the header.
+	pub header_us: u64,
+	/// Microseconds spent parsing the table of contents, including decompressing it if
+	/// it's compressed. Most of this is usually seeking from each entry's index to its
+	/// record, so it grows with [`entry_count`](Self::entry_count).
+	pub toc_parse_us: u64,
+	/// The number of files and directories parsed. With lazy parsing (see
+	/// [`OpenOptions::lazy`]), that's only the root directory's entries.
+	pub entry_count: usize,
+}
+
 impl<'a> IntoIterator for &'a HapiDirectory {
 	type Item = &'a HapiEntry;
 	type IntoIter = std::slice::Iter<'a, HapiEntry>;
@@ -73,7 +159,7 @@ impl HapiDirectory {
 
 	/// Returns just the directory name as a `&str`.
 	pub fn name(&self) -> &str {
-		// SAFETY: NullString -> String conversion already replaced invalid UTF-8
+		// SAFETY: names were decoded into valid UTF-8 when they were parsed
 		self.path.file_name().map_or("", |s| s.to_str().unwrap())
 	}
 
@@ -81,6 +167,200 @@ impl HapiDirectory {
 	pub fn iter(&self) -> <&HapiDirectory as IntoIterator>::IntoIter {
 		self.into_iter()
 	}
+
+	/// Returns an iterator over every entry under this directory, including the contents
+	/// of subdirectories.
+	///
+	/// Each directory is yielded right before its own contents (depth-first, in archive
+	/// order). Unloaded directories (see [`is_loaded`](Self::is_loaded)) are yielded,
+	/// but have nothing under them.
+	pub fn walk(&self) -> Walk<'_> {
+		self.walk_to_depth(usize::MAX)
+	}
+
+	/// Returns an iterator over the entries under this directory down to `max_depth`
+	/// levels deep, along with the depth of each.
+	///
+	/// Entries directly under this directory are at depth 1, their children at depth 2,
+	/// and so on; nothing deeper than `max_depth` is visited, so `iter_depth(1)` is
+	/// like [`iter`](Self::iter) and `iter_depth(usize::MAX)` is like
+	/// [`walk`](Self::walk). Entries come in the same order as from `walk`.
+	pub fn iter_depth(&self, max_depth: usize) -> impl Iterator<Item = (usize, &HapiEntry)> {
+		let mut walk = self.walk_to_depth(max_depth);
+		std::iter::from_fn(move || walk.next_with_depth())
+	}
+
+	fn walk_to_depth(&self, max_depth: usize) -> Walk<'_> {
+		Walk {
+			stack: if max_depth > 0 {
+				vec![self.iter()]
+			} else {
+				Vec::new()
+			},
+			max_depth,
+		}
+	}
+
+	/// Returns `false` if this directory's entries haven't been parsed yet.
+	///
+	/// This only happens for subdirectories of archives opened with
+	/// [`OpenOptions::lazy`]; see [`HapiArchive::load_dir`].
+	pub fn is_loaded(&self) -> bool {
+		self.loaded
+	}
+
+	/// Returns the total decompressed size of every file under this directory, including
+	/// subdirectories.
+	///
+	/// This is added up while the archive is parsed, so it's free to call. Unloaded
+	/// directories (see [`is_loaded`](Self::is_loaded)) count as empty.
+	pub fn total_size(&self) -> u64 {
+		self.total_size
+	}
+
+	/// Returns where this directory's record (its entry count and the offset of its
+	/// entries) is within the archive.
+	pub fn entry_offset(&self) -> u32 {
+		self.offset
+	}
+
+	/// Returns where the index entry naming this directory is within its parent's list
+	/// of entries, or `None` for the root directory.
+	///
+	/// Together with [`entry_offset`](Self::entry_offset), this describes the physical
+	/// layout of the table of contents, e.g. to sort entries by where they're stored.
+	pub fn toc_position(&self) -> Option<u32> {
+		self.toc_position
+	}
+
+	/// Returns the number of files under this directory, including in subdirectories.
+	///
+	/// Like [`total_size`](Self::total_size), this is counted up front, and unloaded
+	/// directories count as empty.
+	pub fn file_count(&self) -> usize {
+		self.file_count
+	}
+
+	/// Returns the number of directories under this directory, including nested ones
+	/// (but not this one).
+	///
+	/// Like [`total_size`](Self::total_size), this is counted up front. Unloaded
+	/// directories are counted, but nothing under them is.
+	pub fn dir_count(&self) -> usize {
+		self.dir_count
+	}
+
+	/// Returns the entry directly under this directory called `name`, if there is one.
+	///
+	/// The comparison is case-sensitive; see [`child_ignore_case`](Self::child_ignore_case)
+	/// for the alternative.
+	pub fn child(&self, name: &str) -> Option<&HapiEntry> {
+		self.iter().find(|entry| entry.name() == name)
+	}
+
+	/// Like [`child`](Self::child), but ignores ASCII case when comparing names.
+	pub fn child_ignore_case(&self, name: &str) -> Option<&HapiEntry> {
+		self.iter()
+			.find(|entry| entry.name().eq_ignore_ascii_case(name))
+	}
+
+	/// Returns the entry at `path` under this directory, if there is one.
+	///
+	/// `path` is relative to this directory, like `units/ARMCOM.FBI`; leading `/` and
+	/// `.` components are ignored, so paths from [`HapiFile::path`] work too. Names
+	/// are compared case-sensitively, and `..` never matches anything. Returns `None`
+	/// for a path with no names in it, since that would be this directory itself.
+	pub fn get(&self, path: impl AsRef<Path>) -> Option<&HapiEntry> {
+		let mut found: Option<&HapiEntry> = None;
+
+		for component in path.as_ref().components() {
+			let name = match component {
+				Component::RootDir | Component::CurDir => continue,
+				Component::Normal(name) => name.to_str()?,
+				Component::ParentDir | Component::Prefix(_) => return None,
+			};
+			let dir = match found {
+				Some(entry) => entry.as_dir()?,
+				None => self,
+			};
+			found = Some(dir.child(name)?);
+		}
+
+		found
+	}
+}
+
+/// Where a file is stored, how big it is and how it's compressed, as collected by
+/// [`HapiArchive::index`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileMeta {
+	/// See [`HapiFile::contents_offset`].
+	pub contents_offset: u32,
+	/// See [`HapiFile::extracted_size`].
+	pub extracted_size: u32,
+	/// See [`HapiFile::compression`].
+	pub compression: HapiCompressionType,
+}
+
+impl From<&HapiFile> for FileMeta {
+	fn from(file: &HapiFile) -> Self {
+		FileMeta {
+			contents_offset: file.contents_offset,
+			extracted_size: file.extracted_size,
+			compression: file.compression,
+		}
+	}
+}
+
+/// An iterator over every entry under a directory, returned by [`HapiDirectory::walk`].
+#[derive(Debug, Clone)]
+pub struct Walk<'a> {
+	stack: Vec<std::slice::Iter<'a, HapiEntry>>,
+	// how many levels the stack may grow to
+	max_depth: usize,
+}
+
+impl<'a> Walk<'a> {
+	// The next entry, along with how many levels down it is
+	fn next_with_depth(&mut self) -> Option<(usize, &'a HapiEntry)> {
+		loop {
+			let depth = self.stack.len();
+			match self.stack.last_mut()?.next() {
+				Some(entry) => {
+					if let HapiEntry::Directory(dir) = entry {
+						if depth < self.max_depth {
+							self.stack.push(dir.iter());
+						}
+					}
+					return Some((depth, entry));
+				}
+				None => {
+					self.stack.pop();
+				}
+			}
+		}
+	}
+}
+
+impl<'a> Iterator for Walk<'a> {
+	type Item = &'a HapiEntry;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_with_depth().map(|(_, entry)| entry)
+	}
+}
+
+impl Index<&str> for HapiDirectory {
+	type Output = HapiEntry;
+
+	/// Returns the entry directly under this directory called `name`.
+	///
+	/// # Panics
+	/// Panics if there's no such entry. Use [`child`](Self::child) to get an `Option` instead.
+	fn index(&self, name: &str) -> &HapiEntry {
+		self.child(name)
+			.unwrap_or_else(|| panic!("no entry named {:?} in {}", name, self.path_str()))
+	}
 }
 
 impl HapiFile {
@@ -97,12 +377,161 @@ impl HapiFile {
 
 	/// Returns just the file name as a `&str`.
 	pub fn name(&self) -> &str {
-		// SAFETY: NullString -> String conversion already replaced invalid UTF-8
+		// SAFETY: names were decoded into valid UTF-8 when they were parsed
 		self.path.file_name().map_or("", |s| s.to_str().unwrap())
 	}
+
+	/// Returns where this file's record (its contents offset, size and compression) is
+	/// within the archive.
+	pub fn entry_offset(&self) -> u32 {
+		self.offset
+	}
+
+	/// Returns where the index entry naming this file is within its directory's list of
+	/// entries; see [`HapiDirectory::toc_position`].
+	pub fn toc_position(&self) -> u32 {
+		self.toc_position
+	}
+
+	/// Returns the file's extension (without the `.`), if it has one.
+	///
+	/// This follows [`Path::extension`]: only the part after the last `.` counts, so
+	/// `ARMCOM.FBI.bak` has the extension `bak`, and names with no `.` (or only a
+	/// leading one) have none. The extension's case is left as-is.
+	pub fn extension(&self) -> Option<&str> {
+		Path::new(self.name())
+			.extension()
+			.map(|s| s.to_str().unwrap())
+	}
+
+	/// Returns the file name without its [`extension`](Self::extension), following
+	/// [`Path::file_stem`].
+	pub fn stem(&self) -> &str {
+		Path::new(self.name())
+			.file_stem()
+			.map_or("", |s| s.to_str().unwrap())
+	}
+
+	/// Returns whether the file's contents are stored compressed, in chunks.
+	pub fn is_compressed(&self) -> bool {
+		self.compression != HapiCompressionType::None
+	}
+
+	/// Returns whether this file is the one at `query`, a path from the archive root like
+	/// `gamedata/SIDEDATA.TDF`.
+	///
+	/// Leading `/` and `./` are ignored on both sides, so a path from
+	/// [`path`](Self::path) matches as well as one without the `./`. Names are compared
+	/// case-sensitively, and `..` never matches anything; see
+	/// [`matches_path_ignore_case`](Self::matches_path_ignore_case) for the alternative.
+	pub fn matches_path(&self, query: impl AsRef<Path>) -> bool {
+		paths_match(&self.path, query.as_ref(), |a, b| a == b)
+	}
+
+	/// Like [`matches_path`](Self::matches_path), but ignores ASCII case when comparing
+	/// names.
+	pub fn matches_path_ignore_case(&self, query: impl AsRef<Path>) -> bool {
+		paths_match(&self.path, query.as_ref(), str::eq_ignore_ascii_case)
+	}
+}
+
+// Compares two archive paths name by name, ignoring leading `/` and `.` components
+fn paths_match(a: &Path, b: &Path, eq: impl Fn(&str, &str) -> bool) -> bool {
+	// `None` for components that can't match anything
+	fn names(path: &Path) -> impl Iterator<Item = Option<&str>> {
+		path.components().filter_map(|component| match component {
+			Component::RootDir | Component::CurDir => None,
+			Component::Normal(name) => Some(name.to_str()),
+			Component::ParentDir | Component::Prefix(_) => Some(None),
+		})
+	}
+
+	let (mut a, mut b) = (names(a), names(b));
+	loop {
+		match (a.next(), b.next()) {
+			(None, None) => return true,
+			(Some(Some(a)), Some(Some(b))) if eq(a, b) => continue,
+			_ => return false,
+		}
+	}
+}
+
+/// Whether an entry is a file or a directory, as returned by [`HapiArchive::entry_kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntryKind {
+	File,
+	Dir,
 }
 
 impl HapiEntry {
+	/// Returns whether this entry is a file or a directory.
+	pub fn kind(&self) -> EntryKind {
+		match self {
+			HapiEntry::File(_) => EntryKind::File,
+			HapiEntry::Directory(_) => EntryKind::Dir,
+		}
+	}
+
+	/// Returns the decompressed size of this entry: a file's
+	/// [`extracted_size`](HapiFile::extracted_size), or a directory's
+	/// [`total_size`](HapiDirectory::total_size).
+	pub fn total_size(&self) -> u64 {
+		match self {
+			HapiEntry::File(file) => file.extracted_size as u64,
+			HapiEntry::Directory(dir) => dir.total_size,
+		}
+	}
+
+	// The flag byte of the index entry naming this entry (0 for the root directory)
+	pub(super) fn flags(&self) -> u8 {
+		match self {
+			HapiEntry::File(file) => file.flags,
+			HapiEntry::Directory(dir) => dir.flags,
+		}
+	}
+
+	// Number of files and directories this entry accounts for, itself included
+	pub(super) fn counts(&self) -> (usize, usize) {
+		match self {
+			HapiEntry::File(_) => (1, 0),
+			HapiEntry::Directory(dir) => (dir.file_count, dir.dir_count + 1),
+		}
+	}
+
+	/// Returns where the entry's record is within the archive; see
+	/// [`HapiFile::entry_offset`] and [`HapiDirectory::entry_offset`].
+	pub fn entry_offset(&self) -> u32 {
+		match self {
+			HapiEntry::File(file) => file.entry_offset(),
+			HapiEntry::Directory(dir) => dir.entry_offset(),
+		}
+	}
+
+	/// Returns where the index entry naming this entry is within the archive; see
+	/// [`HapiDirectory::toc_position`].
+	pub fn toc_position(&self) -> Option<u32> {
+		match self {
+			HapiEntry::File(file) => Some(file.toc_position()),
+			HapiEntry::Directory(dir) => dir.toc_position(),
+		}
+	}
+
+	/// Returns the entry's full path within the archive as a `&str`.
+	pub fn path_str(&self) -> &str {
+		match self {
+			HapiEntry::File(file) => file.path_str(),
+			HapiEntry::Directory(dir) => dir.path_str(),
+		}
+	}
+
+	/// Returns just the entry's name as a `&str`.
+	pub fn name(&self) -> &str {
+		match self {
+			HapiEntry::File(file) => file.name(),
+			HapiEntry::Directory(dir) => dir.name(),
+		}
+	}
+
 	/// Returns `Some(file)` if this entry holds a file; otherwise returns `None`.
 	pub fn as_file(&self) -> Option<&HapiFile> {
 		if let HapiEntry::File(file) = self {
@@ -124,27 +553,255 @@ impl HapiEntry {
 
 impl<R> HapiArchive<R>
 where
-	R: Read + Seek + Debug,
+	R: Read + Seek,
+{
+	// Locks the reader. Everything that uses it seeks before reading, so a panic while
+	// it was locked can't leave it in a state anyone relies on; poisoning is ignored
+	fn reader(&self) -> MutexGuard<'_, HapiReader<R>> {
+		self.reader.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	/// Consumes the archive, returning the stream it was opened from.
+	///
+	/// The stream is left wherever the last read from the archive left it, so seek
+	/// somewhere meaningful before using it.
+	pub fn into_inner(self) -> R {
+		self.reader
+			.into_inner()
+			.unwrap_or_else(PoisonError::into_inner)
+			.into_inner()
+	}
+}
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
 {
 	/// Opens an existing archive for reading.
 	///
 	/// Once you've opened an archive, you can iterate over its entries with [`contents`] and
 	/// pass them to this struct's methods as necessary (or just call [`extract_all`]).
 	///
+	/// The archive takes ownership of `stream`. To keep using a stream afterwards (say,
+	/// to read the rest of a file the archive is embedded in), pass a mutable reference
+	/// to it instead, since `&mut R` can be read from just the same.
+	///
+	/// Every offset in the format is 32 bits, so only the first 4 GiB of `stream` can be
+	/// part of the archive. Bigger streams still open, as long as nothing in the table of
+	/// contents needs to be past that point; if something does, it's reported as
+	/// [`HapiError::Parse`] rather than wrapped around to the wrong place.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	/// use std::io::{Read, Seek, SeekFrom};
+	///
+	/// let mut file = File::open("Example.ufo")?;
+	/// let archive = HapiArchive::open(&mut file)?;
+	/// archive.extract_all("Example")?;
+	/// drop(archive);
+	///
+	/// // `file` is still ours
+	/// let mut footer = Vec::new();
+	/// file.seek(SeekFrom::End(-16))?;
+	/// file.read_to_end(&mut footer)?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	///
 	/// [`contents`]: Self::contents
 	/// [`extract_all`]: Self::extract_all
-	pub fn open(stream: R) -> Result<HapiArchive<R>, Box<dyn Error>> {
+	pub fn open(stream: R) -> Result<HapiArchive<R>, HapiError> {
+		Self::open_with(stream, OpenOptions::default())
+	}
+
+	/// Opens an existing archive, deciphering it with `key` instead of the key stored in
+	/// its header.
+	///
+	/// A shortcut for `HapiArchive::open_with(stream, OpenOptions::new().key(key))`;
+	/// see [`OpenOptions::key`] for details.
+	pub fn open_with_key(stream: R, key: Option<u32>) -> Result<HapiArchive<R>, HapiError> {
+		Self::open_with(stream, OpenOptions::new().key(key))
+	}
+
+	/// Opens an existing archive, decoding entry names with `encoding`.
+	///
+	/// A shortcut for `HapiArchive::open_with(stream, OpenOptions::new().name_encoding(encoding))`;
+	/// see [`NameEncoding`].
+	pub fn open_with_encoding(
+		stream: R,
+		encoding: NameEncoding,
+	) -> Result<HapiArchive<R>, HapiError> {
+		Self::open_with(stream, OpenOptions::new().name_encoding(encoding))
+	}
+
+	/// Reads just the header of `stream` to find out what kind of HAPI file it is.
+	///
+	/// Save files can't be opened yet, so this lets them be told apart from archives
+	/// (and handled some other way) without trying. Streams that aren't HAPI files at
+	/// all fail with [`HapiError::NotAnArchive`]. The stream is left where it started,
+	/// so a `&mut` stream can be opened straight afterwards.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let mut file = File::open("Example.ufo")?;
+	/// match HapiArchive::probe_kind(&mut file)? {
+	/// 	HapiKind::Archive => HapiArchive::open(file)?.extract_all("Example")?,
+	/// 	HapiKind::Save => eprintln!("That's a saved game"),
+	/// 	kind => eprintln!("Don't know what to do with {:?}", kind),
+	/// }
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn probe_kind(stream: R) -> Result<HapiKind, HapiError> {
+		Self::probe_header(stream).map(|header| header.kind())
+	}
+
+	/// Reads just the header of `stream`, leaving the stream where it started.
+	///
+	/// Like [`probe_kind`](Self::probe_kind), but gives the whole header, which can be
+	/// passed on to [`open_with_header`](Self::open_with_header) so it isn't read twice.
+	pub fn probe_header(mut stream: R) -> Result<HapiHeader, HapiError> {
+		let start = stream.stream_position()?;
+		let header = read_header(&mut stream);
+		stream.seek(SeekFrom::Start(start))?;
+		header.map(|(header, _)| header)
+	}
+
+	/// Opens an existing archive whose header has already been read, as by
+	/// [`probe_header`](Self::probe_header).
+	///
+	/// `header` is trusted to be the one at the start of `stream`, which must be
+	/// positioned there, and isn't read again. It's only checked against the stream's
+	/// length (its table of contents has to start within it), and its marker is
+	/// handled as usual. Otherwise, this is the same as [`open`](Self::open).
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let mut file = File::open("Example.ufo")?;
+	/// let header = HapiArchive::probe_header(&mut file)?;
+	/// if header.kind() == HapiKind::Archive {
+	/// 	HapiArchive::open_with_header(file, header)?.extract_all("Example")?;
+	/// }
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn open_with_header(stream: R, header: HapiHeader) -> Result<HapiArchive<R>, HapiError> {
+		let start = Instant::now();
+		let options = OpenOptions::new();
+		let reader = HapiReader::from_parts(
+			stream,
+			header,
+			options.on_unknown_marker,
+			options.on_small_toc,
+		)?;
+		Self::open_reader(reader, options, start).map(|(archive, _)| archive)
+	}
+
+	/// Like [`open`](Self::open), but with the given [`OpenOptions`].
+	pub fn open_with(stream: R, options: OpenOptions) -> Result<HapiArchive<R>, HapiError> {
+		Self::open_timed(stream, options).map(|(archive, _)| archive)
+	}
+
+	/// Like [`open_with`](Self::open_with), but also measures how long each step of
+	/// opening the archive took; see [`OpenTiming`].
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let file = File::open("Huge.ufo")?;
+	/// let (archive, timing) = HapiArchive::open_timed(file, OpenOptions::new())?;
+	/// eprintln!(
+	/// 	"header: {} µs, {} entries in {} µs",
+	/// 	timing.header_us, timing.entry_count, timing.toc_parse_us
+	/// );
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn open_timed(
+		stream: R,
+		options: OpenOptions,
+	) -> Result<(HapiArchive<R>, OpenTiming), HapiError> {
+		let start = Instant::now();
+
 		// Create reader
-		let mut reader = HapiReader::new(stream)?;
+		let reader = HapiReader::new(stream, options.on_unknown_marker, options.on_small_toc)?;
+		Self::open_reader(reader, options, start)
+	}
+
+	// Opens the archive read by `reader`, which was made at `start`
+	fn open_reader(
+		mut reader: HapiReader<R>,
+		options: OpenOptions,
+		start: Instant,
+	) -> Result<(HapiArchive<R>, OpenTiming), HapiError> {
+		if let Some(key) = options.key {
+			reader.header.key = key;
+		}
+		let mut warnings = mem::take(&mut reader.warnings);
+
+		let zlib = options.zlib.unwrap_or_else(|| Arc::new(Libflate));
+		let header_done = Instant::now();
 
 		// Parse table of contents
+		let depth = ParseDepth::of(Path::new("."), options.max_depth);
 		reader.seek(SeekFrom::Start(reader.header.toc_offset as u64))?;
-		let contents = HapiDirectory::read_args(&mut reader, (PathBuf::from("."),))?;
+		let contents = if toc_is_compressed(&mut reader)? {
+			// The whole thing has to be parsed up front, since it can't be loaded from
+			// the archive later
+			let mut toc = CompressedToc::read(&mut reader, &*zlib)?;
+			HapiDirectory::read_args(
+				&mut toc,
+				(PathBuf::from("."), false, options.names, None, depth),
+			)?
+		} else {
+			HapiDirectory::read_args(
+				&mut reader,
+				(PathBuf::from("."), options.lazy, options.names, None, depth),
+			)?
+		};
 
-		Ok(HapiArchive {
-			reader: RefCell::new(reader),
-			root_dir: contents,
-		})
+		// Some variants of the format set other flags, whose meaning is unknown
+		warnings.extend(
+			contents
+				.walk()
+				.filter(|entry| entry.flags() & !HAPI_FLAG_DIR != 0)
+				.map(|entry| HapiWarning::UnknownFlags {
+					path: PathBuf::from(entry.path_str()),
+					flags: entry.flags(),
+				}),
+		);
+
+		let timing = OpenTiming {
+			header_us: (header_done - start).as_micros() as u64,
+			toc_parse_us: header_done.elapsed().as_micros() as u64,
+			entry_count: contents.file_count + contents.dir_count,
+		};
+
+		Ok((
+			HapiArchive {
+				reader: Mutex::new(reader),
+				zlib,
+				pool: options.pool.unwrap_or_else(|| Arc::new(Unpooled)),
+				names: options.names,
+				max_depth: options.max_depth,
+				warnings,
+				root_dir: contents,
+			},
+			timing,
+		))
+	}
+
+	/// Returns anything odd about the archive that was noted instead of stopping it from
+	/// opening, such as an unknown header marker (see [`OpenOptions::on_unknown_marker`]).
+	/// Empty for a well-formed archive.
+	pub fn warnings(&self) -> &[HapiWarning] {
+		&self.warnings
 	}
 
 	/// Returns an iterator over the entries in the archive's root directory.
@@ -152,26 +809,362 @@ where
 		self.root_dir.iter()
 	}
 
+	/// Returns the file at `path` within the archive, if there is one; see
+	/// [`HapiDirectory::get`] for how paths are matched.
+	pub fn get_file(&self, path: impl AsRef<Path>) -> Option<&HapiFile> {
+		self.root_dir.get(path)?.as_file()
+	}
+
+	/// Returns the directory at `path` within the archive, if there is one; see
+	/// [`HapiDirectory::get`] for how paths are matched. A path with no names in it
+	/// (like `.` or `/`) gives the root directory.
+	pub fn get_dir(&self, path: impl AsRef<Path>) -> Option<&HapiDirectory> {
+		let path = path.as_ref();
+		if path
+			.components()
+			.all(|c| matches!(c, Component::RootDir | Component::CurDir))
+		{
+			Some(&self.root_dir)
+		} else {
+			self.root_dir.get(path)?.as_dir()
+		}
+	}
+
+	/// Returns whether `path` is a file or a directory within the archive, or `None` if
+	/// there's nothing there; see [`HapiDirectory::get`] for how paths are matched.
+	/// Like [`get_dir`](Self::get_dir), a path with no names in it is the root directory.
+	pub fn entry_kind(&self, path: impl AsRef<Path>) -> Option<EntryKind> {
+		let path = path.as_ref();
+		match self.root_dir.get(path) {
+			Some(entry) => Some(entry.kind()),
+			None => self.get_dir(path).map(|_| EntryKind::Dir),
+		}
+	}
+
+	/// Returns an iterator over every entry in the archive; see [`HapiDirectory::walk`].
+	pub fn walk(&self) -> Walk<'_> {
+		self.root_dir.walk()
+	}
+
+	/// Returns how deep the deepest entry in the archive is nested.
+	///
+	/// Entries in the root directory are 1 level deep, their children 2, and so on (like
+	/// with [`HapiDirectory::iter_depth`]), so an empty archive gives 0. Like
+	/// [`walk`](Self::walk), this only sees loaded directories when the archive was
+	/// opened lazily. To refuse archives that are too deep in the first place, see
+	/// [`OpenOptions::max_depth`].
+	pub fn max_depth(&self) -> usize {
+		self.root_dir
+			.iter_depth(usize::MAX)
+			.map(|(depth, _)| depth)
+			.max()
+			.unwrap_or(0)
+	}
+
+	/// Builds a sorted index of every file in the archive by path, for when many lookups
+	/// are going to be made.
+	///
+	/// Keys are paths from the archive root without the leading `./`, like
+	/// `units/ARMCOM.FBI`; lookups are case-sensitive. The index is a snapshot, built
+	/// with [`walk`](Self::walk), so for lazily opened archives only loaded directories
+	/// are included.
+	pub fn index(&self) -> BTreeMap<PathBuf, FileMeta> {
+		self.walk()
+			.filter_map(HapiEntry::as_file)
+			.map(|file| {
+				let path = file.path.strip_prefix(".").unwrap_or(&file.path);
+				(path.to_path_buf(), FileMeta::from(file))
+			})
+			.collect()
+	}
+
+	/// Returns the total size of the archive's contents once decompressed, in bytes.
+	///
+	/// This is the sum of every file's [`extracted_size`](HapiFile::extracted_size),
+	/// which is worked out while the archive is opened, so there's no need to walk the
+	/// tree for it. For lazily opened archives, only loaded directories are counted.
+	pub fn total_uncompressed_size(&self) -> u64 {
+		self.root_dir.total_size
+	}
+
+	/// Returns the number of files in the archive. This is counted while the archive is
+	/// opened, so it's free to call; see [`HapiDirectory::file_count`].
+	pub fn count_files(&self) -> usize {
+		self.root_dir.file_count
+	}
+
+	/// Returns the number of directories in the archive, not counting the root. This is
+	/// counted while the archive is opened, so it's free to call; see
+	/// [`HapiDirectory::dir_count`].
+	pub fn count_dirs(&self) -> usize {
+		self.root_dir.dir_count
+	}
+
+	/// Renders the archive's directory structure like `tree(1)` does.
+	///
+	/// The root is shown as `.`, followed by every entry on its own line, indented under
+	/// its directory with `├──`/`└──` connectors. Entries appear in archive order.
+	/// Unloaded directories are shown without contents.
+	///
+	/// ```text
+	/// .
+	/// ├── gamedata
+	/// │   └── SIDEDATA.TDF
+	/// └── Copyright.txt
+	/// ```
+	pub fn tree_string(&self) -> String {
+		let mut tree = String::from(".\n");
+		write_tree(&self.root_dir, "", &mut tree);
+		tree
+	}
+
+	/// Parses the entries directly under `dir`, returning a loaded copy of it.
+	///
+	/// This is how unloaded directories from a lazily opened archive (see
+	/// [`OpenOptions::lazy`]) get expanded. Subdirectories of the result are themselves
+	/// left unloaded. Loading an already loaded directory just parses it again.
+	pub fn load_dir(&self, dir: &HapiDirectory) -> Result<HapiDirectory, HapiError> {
+		let mut reader = self.reader();
+		reader.seek(SeekFrom::Start(dir.offset as u64))?;
+		let mut loaded = HapiDirectory::read_args(
+			&mut *reader,
+			(
+				dir.path.clone(),
+				true,
+				self.names,
+				dir.toc_position,
+				ParseDepth::of(&dir.path, self.max_depth),
+			),
+		)?;
+		loaded.flags = dir.flags;
+		Ok(loaded)
+	}
+
+	// Returns `dir` if it's loaded, otherwise loads it into `storage` and returns that
+	fn ensure_loaded<'d>(
+		&self,
+		dir: &'d HapiDirectory,
+		storage: &'d mut Option<HapiDirectory>,
+	) -> Result<&'d HapiDirectory, HapiError> {
+		if dir.loaded {
+			Ok(dir)
+		} else {
+			Ok(storage.insert(self.load_dir(dir)?))
+		}
+	}
+
 	/// Extracts a file from the archive into the directory denoted by `dest`.
 	///
 	/// If a file with the same name as `entry` already exists in `dest`, it will be
-	/// truncated and overwritten.
-	pub fn extract_file(
+	/// truncated and overwritten. Returns the number of bytes written.
+	///
+	/// `dest` must already exist; if it doesn't, or isn't a directory,
+	/// [`HapiError::DestNotADirectory`] is returned.
+	pub fn extract_file(&self, entry: &HapiFile, dest: impl AsRef<Path>) -> Result<u64, HapiError> {
+		Self::check_dest(dest.as_ref(), false)?;
+
+		self.extract_file_inner(
+			entry,
+			dest.as_ref(),
+			&mut FsSink,
+			&mut ExtractOptions::default(),
+		)
+	}
+
+	fn extract_file_inner(
 		&self,
 		entry: &HapiFile,
-		dest: impl AsRef<Path>,
-	) -> Result<(), Box<dyn Error>> {
-		if !dest.as_ref().metadata()?.is_dir() {
-			return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a directory").into());
+		dest: &Path,
+		sink: &mut impl ExtractSink,
+		options: &mut ExtractOptions,
+	) -> Result<u64, HapiError> {
+		let filename = dest.join(&*options.file_name(entry.name()));
+		self.extract_file_at(entry, filename, sink, options)
+	}
+
+	// Extracts `entry` to exactly `filename`, whose directory has to exist already
+	fn extract_file_at(
+		&self,
+		entry: &HapiFile,
+		filename: PathBuf,
+		sink: &mut impl ExtractSink,
+		options: &mut ExtractOptions,
+	) -> Result<u64, HapiError> {
+		if sink.exists(&filename) {
+			match options.on_conflict {
+				ConflictPolicy::Overwrite => (),
+				ConflictPolicy::Skip => {
+					eprintln!("Skipping existing file {}", filename.display());
+					return Ok(0);
+				}
+				ConflictPolicy::Error => {
+					return Err(io::Error::new(
+						io::ErrorKind::AlreadyExists,
+						format!("File {} already exists", filename.display()),
+					)
+					.into());
+				}
+			}
 		}
 
-		let filename = dest.as_ref().join(entry.name());
+		if let Some(progress) = &mut options.progress {
+			progress(entry, &filename);
+		}
 
 		eprintln!("Creating file {}", filename.to_str().unwrap());
 
-		let mut file = File::create(filename)?;
+		let mut file = CrcWriter::new(
+			sink.create_file(&filename)?,
+			options.manifest_path.is_some(),
+		);
+
+		let written = if let Some(mut transform) = options.transform.take() {
+			// put the callback back before bailing out on errors. The buffer grows as
+			// chunks come in, and write_file_inner holds it to max_output_size, so a
+			// bogus extracted_size can't make us allocate up front
+			let capacity = (entry.extracted_size.min(HAPI_CHUNK_SIZE) as u64)
+				.min(options.max_output_size.unwrap_or(u64::MAX));
+			let mut contents = Vec::with_capacity(capacity as usize);
+			let result = self
+				.write_file_inner(entry, &mut contents, options)
+				.map(|_| transform(entry, &contents));
+			options.transform = Some(transform);
+
+			let contents = result?;
+			file.write_all(&contents)?;
+			contents.len() as u64
+		} else {
+			self.write_file_inner(entry, &mut file, options)?
+		};
+
+		// the file has to be closed first, or it could be written to again afterwards
+		let (file, crc) = file.finish();
+		drop(file);
+		if let Some(crc) = crc {
+			options.record(entry, written, crc);
+		}
+		if let Some(mtime) = options.mtime {
+			sink.set_modified(&filename, mtime)?;
+		}
+		if let Some(paths) = &mut options.written {
+			paths.push(filename);
+		}
+
+		Ok(written)
+	}
+
+	/// Reads the entire contents of a file from the archive into a new `Vec`.
+	pub fn read_file(&self, entry: &HapiFile) -> Result<Vec<u8>, HapiError> {
+		let mut contents = Vec::new();
+		self.read_file_into(entry, &mut contents)?;
+		Ok(contents)
+	}
+
+	/// Reads the entire contents of a file from the archive into `buf`, replacing
+	/// whatever was in it.
+	///
+	/// Unlike [`read_file`](Self::read_file), this reuses `buf`'s allocation, which saves
+	/// a lot of allocating when reading many files in a row. Returns the number of
+	/// bytes read. If reading fails, `buf` is left holding whatever was read so far.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// let mut buf = Vec::new();
+	/// for file in archive.walk().filter_map(HapiEntry::as_file) {
+	/// 	archive.read_file_into(file, &mut buf)?;
+	/// 	println!("{}: {} bytes", file.path_str(), buf.len());
+	/// }
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn read_file_into(&self, entry: &HapiFile, buf: &mut Vec<u8>) -> Result<u64, HapiError> {
+		buf.clear();
+		// extracted_size comes from the archive, so don't trust it with more than a chunk
+		buf.reserve(entry.extracted_size.min(HAPI_CHUNK_SIZE) as usize);
+		self.write_file(entry, buf)
+	}
+
+	/// Reads the entire contents of a file from the archive into a `String`.
+	///
+	/// Invalid UTF-8 is replaced with U+FFFD, as with [`String::from_utf8_lossy`]; most
+	/// text in TA archives is ASCII anyway. Use
+	/// [`read_file_to_string_strict`](Self::read_file_to_string_strict) to get an error
+	/// instead.
+	pub fn read_file_to_string(&self, entry: &HapiFile) -> Result<String, HapiError> {
+		let contents = self.read_file(entry)?;
+		Ok(match String::from_utf8(contents) {
+			Ok(s) => s,
+			Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+		})
+	}
+
+	/// Like [`read_file_to_string`](Self::read_file_to_string), but fails with an
+	/// [`InvalidData`](io::ErrorKind::InvalidData) I/O error if the file isn't valid UTF-8.
+	pub fn read_file_to_string_strict(&self, entry: &HapiFile) -> Result<String, HapiError> {
+		String::from_utf8(self.read_file(entry)?).map_err(|_| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("{} is not valid UTF-8", entry.path_str()),
+			)
+			.into()
+		})
+	}
+
+	/// Opens a file from the archive for streaming reads.
+	///
+	/// Unlike [`write_file`](Self::write_file), this doesn't decompress the whole file up
+	/// front, and the returned reader can seek; see [`HapiFileReader`] for details.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	/// use std::io::{Read, Seek, SeekFrom};
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// let file = archive.contents().find_map(HapiEntry::as_file).unwrap();
+	/// let mut reader = archive.open_file(file)?;
+	/// // Skip straight to the last 16 bytes
+	/// reader.seek(SeekFrom::End(-16))?;
+	/// let mut tail = Vec::new();
+	/// reader.read_to_end(&mut tail)?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn open_file<'a>(
+		&'a self,
+		entry: &'a HapiFile,
+	) -> Result<HapiFileReader<'a, R>, HapiError> {
+		HapiFileReader::new(self, entry)
+	}
 
-		self.write_file(entry, &mut file)
+	/// Reads just the first `n` bytes of a file (or all of it, if it's smaller).
+	///
+	/// Only as many chunks as it takes to get `n` bytes are decompressed, so this is
+	/// much cheaper than [`read_file`](Self::read_file) for looking at the start of a big
+	/// file, like to tell what format it's in.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// for file in archive.walk().filter_map(HapiEntry::as_file) {
+	/// 	if archive.peek_file(file, 4)? == b"RIFF" {
+	/// 		println!("{} is a WAV file", file.path_str());
+	/// 	}
+	/// }
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn peek_file(&self, entry: &HapiFile, n: usize) -> Result<Vec<u8>, HapiError> {
+		let mut contents = Vec::with_capacity(n.min(entry.extracted_size as usize));
+		self.open_file(entry)?
+			.take(n as u64)
+			.read_to_end(&mut contents)?;
+		Ok(contents)
 	}
 
 	/// Writes a file from the archive to an arbitrary output stream.
@@ -179,61 +1172,1082 @@ where
 	/// This is useful for writing to stdout, for example. If you want the library
 	/// to create the file with the correct name for you, use
 	/// [`extract_file`](Self::extract_file).
+	///
+	/// `output` can be a trait object, so a destination picked at runtime (like stdout
+	/// or a file) can be passed as a `&mut dyn Write`.
+	///
+	/// Returns the number of bytes written, which should match the file's
+	/// [`extracted_size`](HapiFile::extracted_size) if the archive is intact.
 	pub fn write_file(
 		&self,
 		entry: &HapiFile,
-		output: &mut impl Write,
-	) -> Result<(), Box<dyn Error>> {
-		self.reader
-			.borrow_mut()
-			.seek(SeekFrom::Start(entry.contents_offset as u64))?;
-		let contents = HapiFileContents::read_args(
-			&mut *self.reader.borrow_mut(),
-			(entry.extracted_size, entry.compression),
-		)?;
+		output: &mut (impl Write + ?Sized),
+	) -> Result<u64, HapiError> {
+		self.write_file_inner(entry, output, &mut ExtractOptions::default())
+	}
+
+	fn write_file_inner(
+		&self,
+		entry: &HapiFile,
+		output: &mut (impl Write + ?Sized),
+		options: &mut ExtractOptions,
+	) -> Result<u64, HapiError> {
+		// Empty files have no chunks (and no data) to read, so don't go looking for any;
+		// some archives don't bother giving them a meaningful offset
+		if entry.extracted_size == 0 {
+			return Ok(0);
+		}
+
+		let limit = options.max_output_size.unwrap_or(u64::MAX);
+		let mut output = LimitedWriter::new(output, limit);
+
+		let total = entry.extracted_size as u64;
+		let mut done = 0;
+		let mut report = |written: u64| {
+			done += written;
+			if let Some(progress) = &mut options.file_progress {
+				progress(entry, done, total);
+			}
+		};
+
+		// Uncompressed files can go straight from the archive to the output
+		if entry.compression == HapiCompressionType::None {
+			let result = self.copy_uncompressed(entry, |piece| {
+				output.write_all(piece)?;
+				report(piece.len() as u64);
+				Ok(())
+			});
+			return match result {
+				Err(HapiError::Io(e)) if is_output_limit_error(&e) => {
+					Err(HapiError::OutputTooLarge {
+						path: entry.path.clone(),
+						limit,
+					})
+				}
+				result => result,
+			};
+		}
+
+		let result = match self.read_contents(entry, options.verify_checksums)? {
+			HapiFileContents::Uncompressed => unreachable!("uncompressed file wasn't copied"),
+			HapiFileContents::Compressed(chunks) if options.decode_threads > 1 => {
+				HapiCompressedChunk::decompress_parallel(
+					&chunks,
+					entry,
+					&*self.zlib,
+					&*self.pool,
+					&mut output,
+					options.decode_threads,
+					limit,
+					report,
+				)
+			}
+			HapiFileContents::Compressed(chunks) => chunks
+				.iter()
+				.map(|chunk| {
+					let written = chunk.decompress(entry, &*self.zlib, &*self.pool, &mut output)?;
+					report(written);
+					Ok(written)
+				})
+				.sum(),
+		};
+
+		match result {
+			Err(HapiError::Io(e)) if is_output_limit_error(&e) => Err(HapiError::OutputTooLarge {
+				path: entry.path.clone(),
+				limit,
+			}),
+			Ok(written) if options.check_file_size && written != entry.extracted_size as u64 => {
+				Err(HapiError::FileSizeMismatch {
+					path: entry.path.clone(),
+					expected: entry.extracted_size as u64,
+					actual: written,
+				})
+			}
+			result => result,
+		}
+	}
+
+	/// Decompresses a file from the archive one chunk at a time, passing each to `on_chunk`.
+	///
+	/// This is like [`write_file`](Self::write_file) for destinations that don't have a
+	/// [`Write`] implementation. Each compressed chunk is handed over as soon as it's
+	/// decompressed, so only one is held in memory at a time; uncompressed files are
+	/// handed over in pieces of the same size (64 KiB). Errors from `on_chunk` stop the
+	/// stream and are returned. Returns the total number of bytes passed to `on_chunk`.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// let file = archive.contents().find_map(HapiEntry::as_file).unwrap();
+	/// let mut contents = Vec::new();
+	/// archive.stream_file(file, |chunk| {
+	/// 	contents.extend_from_slice(chunk);
+	/// 	Ok(())
+	/// })?;
+	/// assert_eq!(contents, archive.read_file(file)?);
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn stream_file(
+		&self,
+		entry: &HapiFile,
+		mut on_chunk: impl FnMut(&[u8]) -> io::Result<()>,
+	) -> Result<u64, HapiError> {
+		if entry.extracted_size == 0 {
+			return Ok(0);
+		}
+
+		if entry.compression == HapiCompressionType::None {
+			return self.copy_uncompressed(entry, on_chunk);
+		}
+
+		match self.read_contents(entry, true)? {
+			HapiFileContents::Uncompressed => unreachable!("uncompressed file wasn't copied"),
+			HapiFileContents::Compressed(chunks) => {
+				let mut buf = self.pool.take(HAPI_CHUNK_SIZE as usize);
+				let mut total = 0;
+				for chunk in &chunks {
+					buf.clear();
+					total += chunk.decompress(entry, &*self.zlib, &*self.pool, &mut buf)?;
+					on_chunk(&buf)?;
+				}
+				self.pool.give(buf);
+				Ok(total)
+			}
+		}
+	}
 
-		match contents {
-			HapiFileContents::Uncompressed(data) => Ok(output.write_all(&data)?),
-			HapiFileContents::Compressed(chunks, ..) => {
-				chunks.iter().try_for_each(|chunk| chunk.decompress(output))
+	// Passes an uncompressed file to `on_piece` 64 KiB at a time, rather than reading it
+	// all into memory first. The lock is only held while each piece is read
+	fn copy_uncompressed(
+		&self,
+		entry: &HapiFile,
+		mut on_piece: impl FnMut(&[u8]) -> io::Result<()>,
+	) -> Result<u64, HapiError> {
+		let size = entry.extracted_size as u64;
+		let mut buf = vec![0; size.min(HAPI_CHUNK_SIZE as u64) as usize];
+		let mut pos = 0;
+
+		while pos < size {
+			let len = (size - pos).min(buf.len() as u64) as usize;
+			{
+				let mut reader = self.reader();
+				reader.seek(SeekFrom::Start(entry.contents_offset as u64 + pos))?;
+				reader.read_exact(&mut buf[..len])?;
 			}
+
+			on_piece(&buf[..len])?;
+			pos += len as u64;
 		}
+
+		Ok(pos)
+	}
+
+	// Reads a (non-empty) file's contents as stored. The seek and the read happen under
+	// the same lock, so another thread can't move the reader in between; decompressing
+	// the result doesn't need it
+	fn read_contents(&self, entry: &HapiFile, verify: bool) -> Result<HapiFileContents, HapiError> {
+		let mut reader = self.reader();
+		reader.seek(SeekFrom::Start(entry.contents_offset as u64))?;
+		Ok(HapiFileContents::read_args(
+			&mut *reader,
+			(entry.extracted_size, entry.compression, verify),
+		)?)
 	}
 
 	/// Extracts the entire contents of the archive into the directory specified by `dest`.
 	///
-	/// A shortcut for `archive.extract_dir(archive.root_dir, dest)`.
-	pub fn extract_all(&self, dest: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+	/// A shortcut for `archive.extract_dir(archive.root_dir, dest)`; `dest` is created
+	/// if it doesn't exist yet.
+	pub fn extract_all(&self, dest: impl AsRef<Path>) -> Result<(), HapiError> {
 		self.extract_dir(&self.root_dir, dest)
 	}
 
+	/// Like [`extract_all`](Self::extract_all), but with the given [`ExtractOptions`].
+	pub fn extract_all_with(
+		&self,
+		dest: impl AsRef<Path>,
+		options: ExtractOptions,
+	) -> Result<(), HapiError> {
+		self.extract_dir_with(&self.root_dir, dest, options)
+	}
+
+	/// Like [`extract_all`](Self::extract_all), but returns the path of every file
+	/// written, in the order they were written.
+	///
+	/// The paths are where the files actually ended up, i.e. `dest` joined with each
+	/// file's (sanitized) path within the archive. Directories aren't listed.
+	pub fn extract_all_listed(&self, dest: impl AsRef<Path>) -> Result<Vec<PathBuf>, HapiError> {
+		self.extract_all_listed_with(dest, ExtractOptions::default())
+	}
+
+	/// Like [`extract_all_listed`](Self::extract_all_listed), but with the given
+	/// [`ExtractOptions`].
+	///
+	/// Files skipped because of [`ConflictPolicy::Skip`], or that failed to extract
+	/// with [`continue_on_error`](ExtractOptions::continue_on_error) set, aren't listed.
+	pub fn extract_all_listed_with(
+		&self,
+		dest: impl AsRef<Path>,
+		mut options: ExtractOptions,
+	) -> Result<Vec<PathBuf>, HapiError> {
+		Self::check_dest(dest.as_ref(), true)?;
+
+		options.written = Some(Vec::new());
+		self.extract_dir_inner(&self.root_dir, dest.as_ref(), &mut FsSink, &mut options)?;
+		options.finish_manifest()?;
+		Ok(options.written.take().unwrap_or_default())
+	}
+
+	/// Extracts the entire contents of the archive into `dest`, passing each file through
+	/// `transform` on the way.
+	///
+	/// A shortcut for `archive.extract_all_with(dest, ExtractOptions::new().transform(transform))`;
+	/// see [`ExtractOptions::transform`].
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// // Convert line endings in text files, pass everything else through
+	/// archive.extract_all_transform("Example", |file, contents| {
+	/// 	if file.name().ends_with(".tdf") {
+	/// 		String::from_utf8_lossy(contents).replace("\r\n", "\n").into_bytes()
+	/// 	} else {
+	/// 		contents.to_vec()
+	/// 	}
+	/// })?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn extract_all_transform(
+		&self,
+		dest: impl AsRef<Path>,
+		transform: impl FnMut(&HapiFile, &[u8]) -> Vec<u8>,
+	) -> Result<(), HapiError> {
+		self.extract_all_with(dest, ExtractOptions::new().transform(transform))
+	}
+
 	/// Extracts the contents of the archive under `dir` into the directory specified by `dest`.
 	///
 	/// Note that the directory itself is not created within `dest`, only its contents.
 	/// Any existing files that collide with a file from the archive will be overwritten.
+	///
+	/// `dest` (and any missing parents) will be created if it doesn't exist. If something
+	/// other than a directory is already there, [`HapiError::DestNotADirectory`] is
+	/// returned.
 	pub fn extract_dir(
 		&self,
 		dir: &HapiDirectory,
 		dest: impl AsRef<Path>,
-	) -> Result<(), Box<dyn Error>> {
-		if !dest.as_ref().metadata()?.is_dir() {
-			return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a directory").into());
+	) -> Result<(), HapiError> {
+		self.extract_dir_with(dir, dest, ExtractOptions::default())
+	}
+
+	/// Like [`extract_dir`](Self::extract_dir), but with the given [`ExtractOptions`].
+	pub fn extract_dir_with(
+		&self,
+		dir: &HapiDirectory,
+		dest: impl AsRef<Path>,
+		mut options: ExtractOptions,
+	) -> Result<(), HapiError> {
+		Self::check_dest(dest.as_ref(), true)?;
+
+		self.extract_dir_inner(dir, dest.as_ref(), &mut FsSink, &mut options)?;
+		options.finish_manifest()
+	}
+
+	/// Extracts the contents of the archive under `dir` into an [`ExtractSink`].
+	///
+	/// This works like [`extract_dir_with`](Self::extract_dir_with), except that
+	/// directories and files are created through `sink` instead of on disk. Paths given
+	/// to the sink are relative, starting from the contents of `dir`.
+	pub fn extract_dir_to(
+		&self,
+		dir: &HapiDirectory,
+		sink: &mut impl ExtractSink,
+		mut options: ExtractOptions,
+	) -> Result<(), HapiError> {
+		self.extract_dir_inner(dir, Path::new(""), sink, &mut options)?;
+		options.finish_manifest()
+	}
+
+	/// Extracts `entry` into `dest`, whether it's a file or a directory.
+	///
+	/// Files are extracted like with [`extract_file`](Self::extract_file). Directories
+	/// are created within `dest` under their own name, and their contents extracted into
+	/// them; this differs from [`extract_dir`](Self::extract_dir), which only extracts
+	/// the contents. `dest` is created if it doesn't exist yet.
+	pub fn extract_entry(
+		&self,
+		entry: &HapiEntry,
+		dest: impl AsRef<Path>,
+	) -> Result<(), HapiError> {
+		Self::check_dest(dest.as_ref(), true)?;
+
+		self.extract_entry_inner(
+			entry,
+			dest.as_ref(),
+			&mut FsSink,
+			&mut ExtractOptions::default(),
+		)
+	}
+
+	fn extract_entry_inner(
+		&self,
+		entry: &HapiEntry,
+		dest: &Path,
+		sink: &mut impl ExtractSink,
+		options: &mut ExtractOptions,
+	) -> Result<(), HapiError> {
+		match entry {
+			HapiEntry::File(file) => self
+				.extract_file_inner(file, dest, sink, options)
+				.map(|_| ()),
+			HapiEntry::Directory(dir) => {
+				let dest = dest.join(&*options.file_name(dir.name()));
+				eprintln!("Creating dir {}", dest.to_str().unwrap());
+				create_dest_dir(sink, &dest)?;
+				self.extract_dir_inner(dir, &dest, sink, options)
+			}
 		}
+	}
 
-		eprintln!("Extracting to {}", dest.as_ref().to_str().unwrap());
+	/// Extracts every file whose extension is one of `extensions` into `dest`, returning
+	/// how many were extracted.
+	///
+	/// Extensions are compared case-insensitively, and may be given with or without a
+	/// leading `.`. With `preserve_structure`, files keep their place in the archive's
+	/// directory tree (only creating the directories that hold a match); without it,
+	/// they all go straight into `dest`, and files with the same name overwrite each
+	/// other. `dest` is created if it doesn't exist yet.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// archive.extract_by_extension("Graphics", &["gaf", ".pcx"], false)?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn extract_by_extension(
+		&self,
+		dest: impl AsRef<Path>,
+		extensions: &[&str],
+		preserve_structure: bool,
+	) -> Result<usize, HapiError> {
+		Self::check_dest(dest.as_ref(), true)?;
+
+		let extensions: Vec<_> = extensions
+			.iter()
+			.map(|ext| ext.strip_prefix('.').unwrap_or(ext))
+			.collect();
+		let mut count = 0;
+		self.extract_by_extension_inner(
+			&self.root_dir,
+			dest.as_ref(),
+			&extensions,
+			preserve_structure,
+			&mut ExtractOptions::default(),
+			&mut count,
+		)?;
+		Ok(count)
+	}
+
+	fn extract_by_extension_inner(
+		&self,
+		dir: &HapiDirectory,
+		dest: &Path,
+		extensions: &[&str],
+		preserve_structure: bool,
+		options: &mut ExtractOptions,
+		count: &mut usize,
+	) -> Result<(), HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
 
 		for entry in dir {
 			match entry {
-				HapiEntry::File(file) => self.extract_file(file, dest.as_ref())?,
-				HapiEntry::Directory(dir) => {
-					let dest = dest.as_ref().join(dir.name()); // FIXME check for errant path separators
-					eprintln!("Creating dir {}", dest.to_str().unwrap());
-					fs::create_dir_all(&dest)?;
-					self.extract_dir(dir, dest)?;
+				HapiEntry::File(file) => {
+					let matches = file.extension().is_some_and(|ext| {
+						extensions
+							.iter()
+							.any(|wanted| ext.eq_ignore_ascii_case(wanted))
+					});
+					if matches {
+						create_dest_dir(&mut FsSink, dest)?;
+						self.extract_file_inner(file, dest, &mut FsSink, options)?;
+						*count += 1;
+					}
+				}
+				HapiEntry::Directory(subdir) => {
+					let subdest = if preserve_structure {
+						dest.join(&*options.file_name(subdir.name()))
+					} else {
+						dest.to_path_buf()
+					};
+					self.extract_by_extension_inner(
+						subdir,
+						&subdest,
+						extensions,
+						preserve_structure,
+						options,
+						count,
+					)?;
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Extracts every file to wherever `mapping` says it should go, returning how many
+	/// were extracted.
+	///
+	/// `mapping` is called with each file's path within the archive, relative to the
+	/// root (without the leading `./`) and with each name sanitized like when extracting
+	/// normally, so it has no `..` in it. It returns the full path to write the file to,
+	/// or `None` to skip the file. Directories are created as needed, so directories
+	/// with no files that get extracted aren't created at all.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	/// use std::path::Path;
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// // Units go under game/units, sounds under audio, and nothing else is extracted
+	/// archive.extract_with_mapping(|path| {
+	/// 	if path.starts_with("units") {
+	/// 		Some(Path::new("game").join(path))
+	/// 	} else {
+	/// 		let rest = path.strip_prefix("sounds").ok()?;
+	/// 		Some(Path::new("audio").join(rest))
+	/// 	}
+	/// })?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn extract_with_mapping(
+		&self,
+		mut mapping: impl FnMut(&Path) -> Option<PathBuf>,
+	) -> Result<usize, HapiError> {
+		let mut count = 0;
+		self.extract_mapped_inner(
+			&self.root_dir,
+			&mut mapping,
+			&mut ExtractOptions::default(),
+			&mut count,
+		)?;
+		Ok(count)
+	}
+
+	fn extract_mapped_inner(
+		&self,
+		dir: &HapiDirectory,
+		mapping: &mut impl FnMut(&Path) -> Option<PathBuf>,
+		options: &mut ExtractOptions,
+		count: &mut usize,
+	) -> Result<(), HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			match entry {
+				HapiEntry::File(file) => {
+					let path: PathBuf = file
+						.path()
+						.components()
+						.filter_map(|component| match component {
+							Component::Normal(name) => {
+								Some(options.file_name(&name.to_string_lossy()).into_owned())
+							}
+							_ => None,
+						})
+						.collect();
+
+					if let Some(filename) = mapping(&path) {
+						if let Some(parent) = filename.parent() {
+							if !parent.as_os_str().is_empty() {
+								create_dest_dir(&mut FsSink, parent)?;
+							}
+						}
+						self.extract_file_at(file, filename, &mut FsSink, options)?;
+						*count += 1;
+					}
+				}
+				HapiEntry::Directory(subdir) => {
+					self.extract_mapped_inner(subdir, mapping, options, count)?
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// Makes sure `dest` is a directory we can extract into, creating it if asked to
+	fn check_dest(dest: &Path, create: bool) -> Result<(), HapiError> {
+		match dest.metadata() {
+			Ok(meta) if meta.is_dir() => Ok(()),
+			Err(e) if e.kind() == io::ErrorKind::NotFound && create => {
+				eprintln!("Creating dir {}", dest.to_str().unwrap());
+				Ok(fs::create_dir_all(dest)?)
+			}
+			Ok(_) | Err(_) => Err(HapiError::DestNotADirectory {
+				path: dest.to_path_buf(),
+			}),
+		}
+	}
+
+	fn extract_dir_inner(
+		&self,
+		dir: &HapiDirectory,
+		dest: &Path,
+		sink: &mut impl ExtractSink,
+		options: &mut ExtractOptions,
+	) -> Result<(), HapiError> {
+		eprintln!("Extracting to {}", dest.to_str().unwrap());
+
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			match self.extract_entry_inner(entry, dest, sink, options) {
+				Err(e) if options.continue_on_error => {
+					eprintln!("Warning: failed to extract {}: {}", entry.path_str(), e)
 				}
+				result => result?,
 			}
 		}
 
 		Ok(())
 	}
 }
+
+/// A stream an archive can be read from, as a trait object.
+///
+/// Anything that's [`Read`], [`Seek`] and [`Send`] is a `ReadSeek`, so a
+/// `Box<dyn ReadSeek>` can hold a file, an in-memory buffer or anything else, picked at
+/// runtime; see [`HapiArchive::open_dyn`].
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+impl Debug for dyn ReadSeek + '_ {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("dyn ReadSeek")
+	}
+}
+
+impl HapiArchive<Box<dyn ReadSeek>> {
+	/// Opens an archive from a boxed stream of any type.
+	///
+	/// This is [`open`](Self::open) for when the kind of stream isn't known until
+	/// runtime, so the archive's type stays the same whichever it is.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::{self, File};
+	/// use std::io::Cursor;
+	///
+	/// # let in_memory = false;
+	/// let stream: Box<dyn ReadSeek> = if in_memory {
+	/// 	Box::new(Cursor::new(fs::read("Example.ufo")?))
+	/// } else {
+	/// 	Box::new(File::open("Example.ufo")?)
+	/// };
+	/// let archive = HapiArchive::open_dyn(stream)?;
+	/// archive.extract_all("Example")?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn open_dyn(stream: Box<dyn ReadSeek>) -> Result<Self, HapiError> {
+		Self::open(stream)
+	}
+}
+
+impl<'a> HapiArchive<io::Cursor<&'a [u8]>> {
+	/// Opens an archive that's already in memory, without copying it.
+	///
+	/// The archive borrows `data`, so it can be part of a bigger buffer, like a
+	/// memory-mapped file or an archive embedded in something else. Offsets within the
+	/// archive are from the start of `data`.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs;
+	///
+	/// let data = fs::read("Example.ufo")?;
+	/// let archive = HapiArchive::open_slice(&data)?;
+	/// archive.extract_all("Example")?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn open_slice(data: &'a [u8]) -> Result<Self, HapiError> {
+		Self::open(io::Cursor::new(data))
+	}
+}
+
+/// Extracts the whole archive at `archive` into `dest`, in one go.
+///
+/// A shortcut for opening the file with [`HapiArchive::open`] and calling
+/// [`extract_all`](HapiArchive::extract_all) on it, so `dest` (and any missing parents)
+/// is created if it doesn't exist yet.
+///
+/// # Examples
+/// ```no_run
+/// hapi::extract("Example.ufo", "Example")?;
+/// # Ok::<(), hapi::HapiError>(())
+/// ```
+pub fn extract(archive: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<(), HapiError> {
+	HapiArchive::open(File::open(archive)?)?.extract_all(dest)
+}
+
+// Creates a directory to extract into, explaining what went wrong if something other than
+// a directory is already there
+fn create_dest_dir(sink: &mut impl ExtractSink, path: &Path) -> Result<(), HapiError> {
+	match sink.create_dir(path) {
+		Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(HapiError::DestConflict {
+			path: path.to_path_buf(),
+		}),
+		result => Ok(result?),
+	}
+}
+
+// Appends a line for each entry under `dir` to `tree`, each starting with `prefix`
+fn write_tree(dir: &HapiDirectory, prefix: &str, tree: &mut String) {
+	let count = dir.contents.len();
+
+	for (i, entry) in dir.iter().enumerate() {
+		let last = i + 1 == count;
+		let connector = if last { "└── " } else { "├── " };
+		tree.push_str(prefix);
+		tree.push_str(connector);
+		tree.push_str(entry.name());
+		tree.push('\n');
+
+		if let HapiEntry::Directory(subdir) = entry {
+			let indent = if last { "    " } else { "│   " };
+			write_tree(subdir, &format!("{}{}", prefix, indent), tree);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	fn read_path<R: Read + Seek>(archive: &HapiArchive<R>, path: &str) -> Vec<u8> {
+		archive.read_file(archive.get_file(path).unwrap()).unwrap()
+	}
+
+	fn key_of<R: Read + Seek>(archive: &HapiArchive<R>) -> Option<u32> {
+		archive.reader.lock().unwrap().header.key
+	}
+
+	#[test]
+	fn key_override_forces_cipher_on() {
+		// enciphered, but the header says it isn't
+		let data = Fixture::new(sample()).key(0x5a).stored_key(0).build();
+		let lied_to = HapiArchive::open_slice(&data);
+		assert!(lied_to.map_or(true, |archive| archive.get_file("Copyright.txt").is_none()));
+
+		let archive = HapiArchive::open_with_key(io::Cursor::new(&data), Some(0x5a)).unwrap();
+		assert_eq!(key_of(&archive), Some(0x5a));
+		assert_eq!(
+			read_path(&archive, "units/ARMCOM.FBI"),
+			b"[UNITINFO]\n{\n}\n"
+		);
+	}
+
+	#[test]
+	fn key_override_forces_cipher_off() {
+		// plain, but the header says it's enciphered
+		let data = Fixture::new(sample()).stored_key(encode_key(0x5a)).build();
+		let lied_to = HapiArchive::open_slice(&data);
+		assert!(lied_to.map_or(true, |archive| archive.get_file("Copyright.txt").is_none()));
+
+		let archive = HapiArchive::open_with_key(io::Cursor::new(&data), None).unwrap();
+		assert_eq!(key_of(&archive), None);
+		assert_eq!(
+			read_path(&archive, "units/ARMCOM.FBI"),
+			b"[UNITINFO]\n{\n}\n"
+		);
+	}
+
+	#[test]
+	fn key_override_matching_header_changes_nothing() {
+		for key in [None, Some(0x5a)] {
+			let mut fixture = Fixture::new(sample());
+			if let Some(key) = key {
+				fixture = fixture.key(key);
+			}
+			let data = fixture.build();
+			let archive = HapiArchive::open_slice(&data).unwrap();
+			let overridden = HapiArchive::open_with_key(io::Cursor::new(&data), key).unwrap();
+			assert_eq!(key_of(&archive), key.map(|key| decode_key(encode_key(key))));
+			assert_eq!(key_of(&overridden), key);
+			assert_eq!(
+				read_path(&archive, "anims/big.gaf"),
+				read_path(&overridden, "anims/big.gaf")
+			);
+		}
+	}
+
+	#[test]
+	fn empty_files_extract_empty() {
+		let data = Fixture::new(vec![file("compressed.tdf", ""), raw_file("raw.tdf", "")]).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert!(archive.is_valid().unwrap());
+
+		let tmp = TempDir::new();
+		archive.extract_all(tmp.path()).unwrap();
+		for name in ["compressed.tdf", "raw.tdf"] {
+			let file = archive.get_file(name).unwrap();
+			assert_eq!(file.extracted_size, 0);
+			assert!(archive.read_file(file).unwrap().is_empty());
+			assert_eq!(fs::metadata(tmp.path().join(name)).unwrap().len(), 0);
+		}
+	}
+
+	#[test]
+	fn trailing_padding_is_ignored() {
+		let data = Fixture::new(sample()).key(0x5a).trailing(&[0; 512]).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert!(archive.is_valid().unwrap());
+		assert_eq!(read_path(&archive, "anims/big.gaf"), pattern(200_000));
+	}
+
+	#[test]
+	fn extension_and_stem() {
+		let names = [
+			("ARMCOM.FBI", Some("FBI"), "ARMCOM"),
+			("ARMCOM.FBI.bak", Some("bak"), "ARMCOM.FBI"),
+			("a.b.c.", Some(""), "a.b.c"),
+			("README", None, "README"),
+			(".hidden", None, ".hidden"),
+		];
+		let data = Fixture::new(names.iter().map(|(name, ..)| file(*name, "")).collect()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+
+		for (name, extension, stem) in names {
+			let file = archive.get_file(name).unwrap();
+			assert_eq!(file.extension(), extension, "extension of {name}");
+			assert_eq!(file.stem(), stem, "stem of {name}");
+		}
+	}
+
+	#[test]
+	fn extracts_from_several_threads_at_once() {
+		let data = Fixture::new(sample()).key(0x5a).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let expected = TempDir::new();
+		archive.extract_all(expected.path()).unwrap();
+
+		let dests: Vec<_> = (0..8).map(|_| TempDir::new()).collect();
+		std::thread::scope(|scope| {
+			for dest in &dests {
+				let archive = &archive;
+				scope.spawn(move || archive.extract_all(dest.path()).unwrap());
+			}
+		});
+
+		for dest in &dests {
+			for path in files_under(expected.path()) {
+				assert_eq!(
+					fs::read(dest.path().join(&path)).unwrap(),
+					fs::read(expected.path().join(&path)).unwrap(),
+					"{}",
+					path.display()
+				);
+			}
+			assert_eq!(files_under(dest.path()), files_under(expected.path()));
+		}
+	}
+
+	#[test]
+	fn write_file_in_a_tight_loop() {
+		let data = Fixture::new(sample()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let files: Vec<_> = archive.walk().filter_map(HapiEntry::as_file).collect();
+		assert_eq!(files.len(), 6);
+
+		let mut out = Vec::new();
+		for _ in 0..100 {
+			for file in &files {
+				out.clear();
+				let written = archive.write_file(file, &mut out).unwrap();
+				assert_eq!(written, file.extracted_size as u64);
+				assert_eq!(out.len(), file.extracted_size as usize);
+			}
+		}
+	}
+
+	#[test]
+	fn streamed_chunks_make_up_the_file() {
+		let mut entries = sample();
+		entries.push(raw_file("raw.gaf", pattern(150_000)));
+		let data = Fixture::new(entries).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+
+		for file in archive.walk().filter_map(HapiEntry::as_file) {
+			let mut streamed = Vec::new();
+			let mut calls = 0;
+			let total = archive
+				.stream_file(file, |chunk| {
+					assert!(chunk.len() <= HAPI_CHUNK_SIZE as usize);
+					streamed.extend_from_slice(chunk);
+					calls += 1;
+					Ok(())
+				})
+				.unwrap();
+			assert_eq!(
+				streamed,
+				archive.read_file(file).unwrap(),
+				"{}",
+				file.path_str()
+			);
+			assert_eq!(total, streamed.len() as u64);
+			assert_eq!(calls, streamed.len().div_ceil(HAPI_CHUNK_SIZE as usize));
+		}
+	}
+
+	#[test]
+	fn empty_toc_opens_empty() {
+		let mut data = Fixture::new(Vec::new()).build();
+		// an empty root's entries pointer is meaningless, so it mustn't be followed
+		data[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(archive.root_dir.iter().count(), 0);
+		assert_eq!(archive.walk().count(), 0);
+
+		let tmp = TempDir::new();
+		let dest = tmp.path().join("out");
+		archive.extract_all(&dest).unwrap();
+		assert!(files_under(tmp.path()).is_empty());
+	}
+
+	#[test]
+	fn unknown_marker_follows_policy() {
+		let mut data = Fixture::new(sample()).build();
+		data[4..8].copy_from_slice(b"ODD!");
+		let open = |policy| {
+			let options = OpenOptions::new().on_unknown_marker(policy);
+			HapiArchive::open_with(io::Cursor::new(&data), options)
+		};
+
+		let warned = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(warned.warnings(), [HapiWarning::UnknownMarker(*b"ODD!")]);
+		assert_eq!(warned.count_files(), 6);
+		assert!(open(UnknownMarkerPolicy::Proceed)
+			.unwrap()
+			.warnings()
+			.is_empty());
+		assert!(matches!(
+			open(UnknownMarkerPolicy::Error),
+			Err(HapiError::UnknownMarker(marker)) if marker == *b"ODD!"
+		));
+
+		let normal = Fixture::new(sample()).build();
+		assert!(HapiArchive::open_slice(&normal)
+			.unwrap()
+			.warnings()
+			.is_empty());
+	}
+
+	#[test]
+	fn unknown_flags_are_warned_about() {
+		let mut data = Fixture::new(sample()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let units = archive.get_dir("units").unwrap().toc_position().unwrap() as usize;
+		data[units + 8] = 3;
+
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(
+			archive.warnings(),
+			[HapiWarning::UnknownFlags {
+				path: PathBuf::from("./units"),
+				flags: 3,
+			}]
+		);
+		// the directory bit still counts
+		assert!(archive.get_dir("units/sub").is_some());
+	}
+
+	#[test]
+	fn is_compressed() {
+		let data = Fixture::new(sample()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert!(!archive.get_file("Copyright.txt").unwrap().is_compressed());
+		assert!(archive
+			.get_file("units/ARMCOM.FBI")
+			.unwrap()
+			.is_compressed());
+		assert!(archive.get_file("anims/big.gaf").unwrap().is_compressed());
+	}
+
+	#[test]
+	fn small_toc_follows_policy() {
+		// just the root directory's record, which is as small as it gets
+		let empty = Fixture::new(Vec::new()).build();
+		assert_eq!(empty[8..12], 28u32.to_le_bytes());
+		assert!(HapiArchive::open_slice(&empty)
+			.unwrap()
+			.warnings()
+			.is_empty());
+
+		let mut data = Fixture::new(sample()).build();
+		data[8..12].copy_from_slice(&27u32.to_le_bytes());
+		let open = |policy| {
+			let options = OpenOptions::new().on_small_toc(policy);
+			HapiArchive::open_with(io::Cursor::new(&data), options)
+		};
+
+		let warned = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(warned.warnings(), [HapiWarning::SmallToc { toc_size: 27 }]);
+		assert!(open(SmallTocPolicy::Proceed).unwrap().warnings().is_empty());
+		assert!(matches!(
+			open(SmallTocPolicy::Error),
+			Err(HapiError::CorruptHeader(_))
+		));
+		// the marker's policy has nothing to do with it
+		let options = OpenOptions::new().on_unknown_marker(UnknownMarkerPolicy::Error);
+		assert!(HapiArchive::open_with(io::Cursor::new(&data), options).is_ok());
+	}
+
+	#[test]
+	fn bad_checksum_fails_checked_reads_only() {
+		let data = archive_with_bad_checksum();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert!(!archive.is_valid().unwrap());
+
+		let bad = archive.get_file("bad.txt").unwrap();
+		assert!(matches!(archive.read_file(bad), Err(HapiError::Parse(_))));
+		let mut contents = Vec::new();
+		archive
+			.write_file_inner(
+				bad,
+				&mut contents,
+				&mut ExtractOptions::new().verify_checksums(false),
+			)
+			.unwrap();
+		assert_eq!(contents, b"the checksum doesn't match this");
+
+		let good = archive.get_file("good.txt").unwrap();
+		assert_eq!(archive.read_file(good).unwrap(), b"this one is fine");
+	}
+
+	#[test]
+	fn truncated_chunk_is_an_error() {
+		let data = archive_with_truncated_chunk();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert!(!archive.is_valid().unwrap());
+
+		let cut = archive.get_file("cut.gaf").unwrap();
+		assert!(matches!(
+			archive.read_file(cut),
+			Err(HapiError::ChunkTooLarge { .. })
+		));
+		let whole = archive.get_file("whole.txt").unwrap();
+		assert_eq!(archive.read_file(whole).unwrap(), b"all here");
+	}
+
+	#[test]
+	fn nesting_limit() {
+		// the file is the 64th level down
+		let data = Fixture::new(nested(63)).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(archive.max_depth(), 64);
+
+		// and here it's the 65th
+		let data = Fixture::new(nested(64)).build();
+		assert!(matches!(
+			HapiArchive::open_slice(&data),
+			Err(HapiError::TooDeep { limit: 64, .. })
+		));
+		let options = OpenOptions::new().max_depth(None);
+		let archive = HapiArchive::open_with(io::Cursor::new(&data), options).unwrap();
+		assert_eq!(archive.max_depth(), 65);
+	}
+
+	#[test]
+	fn cyclic_entry_is_too_deep() {
+		let data = archive_with_cyclic_entry();
+		assert!(matches!(
+			HapiArchive::open_slice(&data),
+			Err(HapiError::TooDeep { .. })
+		));
+	}
+
+	#[test]
+	fn empty_names_extract_inside_dest() {
+		let data = archive_with_empty_name();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(archive.count_files(), 2);
+
+		let tmp = TempDir::new();
+		let dest = tmp.path().join("out");
+		archive.extract_all(&dest).unwrap();
+		assert_eq!(files_under(tmp.path()).len(), 2);
+		assert!(files_under(tmp.path())
+			.iter()
+			.all(|path| path.starts_with("out")));
+	}
+
+	#[test]
+	fn dotdot_names_extract_inside_dest() {
+		let data = archive_with_dotdot_name();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(archive.count_files(), 2);
+
+		let tmp = TempDir::new();
+		let dest = tmp.path().join("out");
+		archive.extract_all(&dest).unwrap();
+		assert_eq!(
+			files_under(tmp.path()),
+			[Path::new("out/_/escaped.txt"), Path::new("out/sub/_")]
+		);
+	}
+
+	#[test]
+	fn transform_respects_output_limit() {
+		let data = Fixture::new(sample()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let tmp = TempDir::new();
+
+		let mut largest = 0;
+		let options = ExtractOptions::new()
+			.max_output_size(1000)
+			.transform(|_, contents| {
+				largest = largest.max(contents.len());
+				contents.to_vec()
+			});
+		let result = archive.extract_all_with(tmp.path(), options);
+		assert!(matches!(
+			result,
+			Err(HapiError::OutputTooLarge { limit: 1000, .. })
+		));
+		assert!(largest <= 1000);
+	}
+
+	#[test]
+	fn read_file_into_does_not_trust_extracted_size() {
+		let mut data = Fixture::new(vec![raw_file("huge.txt", "abc")]).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let record = archive.get_file("huge.txt").unwrap().entry_offset() as usize + 4;
+		data[record..record + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let mut buf = Vec::new();
+		assert!(archive
+			.read_file_into(archive.get_file("huge.txt").unwrap(), &mut buf)
+			.is_err());
+		assert!(buf.capacity() <= HAPI_CHUNK_SIZE as usize);
+	}
+}
@@ -0,0 +1,402 @@
+use super::*;
+
+use binrw::{BinReaderExt, VecArgs};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Details about one compressed chunk of a [`HapiFile`], as returned by
+/// [`HapiArchive::chunks`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkInfo {
+	/// How the chunk is compressed. Each chunk declares its own compression, so chunks
+	/// of the same file don't have to agree with each other or with
+	/// [`HapiFile::compression`].
+	pub compression: HapiCompressionType,
+	/// Whether the chunk's data is enciphered, on top of any archive-wide cipher.
+	pub is_enciphered: bool,
+	/// The size of the chunk's data as stored, in bytes (not counting its header).
+	pub compressed_size: u32,
+	/// The size of the chunk's data once decompressed, according to its header.
+	pub decompressed_size: u32,
+	/// Whether the chunk's data matches the checksum in its header.
+	pub checksum_ok: bool,
+}
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Returns details about each compressed chunk of `entry`, in order.
+	///
+	/// Files stored without compression (and empty files) have no chunks, so this
+	/// returns an empty `Vec` for them. Bad checksums aren't an error here; check
+	/// [`ChunkInfo::checksum_ok`] instead.
+	pub fn chunks(&self, entry: &HapiFile) -> Result<Vec<ChunkInfo>, HapiError> {
+		if entry.compression == HapiCompressionType::None || entry.extracted_size == 0 {
+			return Ok(Vec::new());
+		}
+
+		Ok(match self.read_contents(entry, false)? {
+			HapiFileContents::Uncompressed => Vec::new(),
+			HapiFileContents::Compressed(chunks) => chunks
+				.iter()
+				.map(|chunk| ChunkInfo {
+					compression: chunk.compression,
+					is_enciphered: chunk.is_enciphered,
+					compressed_size: chunk.data.len() as u32,
+					decompressed_size: chunk.decompressed_size,
+					checksum_ok: chunk.checksum_ok(),
+				})
+				.collect(),
+		})
+	}
+
+	/// Finds compressed chunks that are likely identical, grouped by their checksum.
+	///
+	/// Each key is a checksum shared by more than one chunk in the archive, mapped to
+	/// the path of each file with such a chunk and the chunk's position within it.
+	/// Only chunk headers are read, so this is quick even for big archives, but it's a
+	/// first pass: chunks with the same checksum aren't necessarily the same, and need
+	/// their data compared to be sure.
+	pub fn duplicate_chunks(&self) -> Result<HashMap<u32, Vec<(PathBuf, usize)>>, HapiError> {
+		let mut seen: HashMap<u32, Vec<(PathBuf, usize)>> = HashMap::new();
+		self.collect_chunk_checksums(&self.root_dir, &mut seen)?;
+
+		seen.retain(|_, chunks| chunks.len() > 1);
+		Ok(seen)
+	}
+
+	/// Finds files that share their data with other files, grouped by where it starts.
+	///
+	/// Each key is a [`contents_offset`](HapiFile::contents_offset) used by more than
+	/// one file, mapped to those files, in the order of [`walk`](Self::walk). Archives
+	/// do this to store identical files once; extracting still writes out each file.
+	/// Empty files are left out, since their offset doesn't mean anything. Nothing is
+	/// read from the archive, so like `walk`, only loaded directories are seen.
+	pub fn shared_data_files(&self) -> HashMap<u32, Vec<&HapiFile>> {
+		let mut files: HashMap<u32, Vec<&HapiFile>> = HashMap::new();
+		for file in self.walk().filter_map(HapiEntry::as_file) {
+			if file.extracted_size > 0 {
+				files.entry(file.contents_offset).or_default().push(file);
+			}
+		}
+
+		files.retain(|_, files| files.len() > 1);
+		files
+	}
+
+	fn collect_chunk_checksums(
+		&self,
+		dir: &HapiDirectory,
+		seen: &mut HashMap<u32, Vec<(PathBuf, usize)>>,
+	) -> Result<(), HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			match entry {
+				HapiEntry::File(file) => {
+					let ranges = self.chunk_ranges(file)?;
+					let mut reader = self.reader();
+					for (index, range) in ranges.into_iter().enumerate() {
+						reader.seek(SeekFrom::Start(range.start))?;
+						let header: HapiChunkHeader = reader.read_le()?;
+						seen.entry(header.checksum)
+							.or_default()
+							.push((file.path.clone(), index));
+					}
+				}
+				HapiEntry::Directory(dir) => self.collect_chunk_checksums(dir, seen)?,
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Returns the range of bytes `entry`'s data takes up in the archive, as stored.
+	///
+	/// For compressed files, this covers the table of chunk sizes at the start of the
+	/// file's contents and every chunk after it; for uncompressed ones, it's simply
+	/// [`extracted_size`](HapiFile::extracted_size) bytes from the start. Only the size
+	/// table is read, so nothing gets decompressed. Offsets are from the start of the
+	/// stream the archive was opened from, and the data in range is enciphered if the
+	/// archive is.
+	pub fn file_data_range(&self, entry: &HapiFile) -> Result<Range<u64>, HapiError> {
+		let start = entry.contents_offset as u64;
+
+		if entry.compression == HapiCompressionType::None || entry.extracted_size == 0 {
+			return Ok(start..start + entry.extracted_size as u64);
+		}
+
+		Ok(start
+			..self
+				.chunk_ranges(entry)?
+				.last()
+				.map_or(start, |range| range.end))
+	}
+
+	/// Returns the ranges of bytes in the archive that nothing refers to, in order.
+	///
+	/// Everything is unused except the header, the table of contents (from where it
+	/// starts up to [`toc_size`](HapiHeader::toc_size), or the chunks it's stored in if
+	/// it's compressed) and each file's data (see
+	/// [`file_data_range`](Self::file_data_range)), so this finds padding, leftovers
+	/// from editing the archive, and anything hidden in between. Anything after the
+	/// last used byte is included too. Overlapping data is fine; each byte only needs
+	/// to be used once. Directories are loaded as needed.
+	pub fn unused_ranges(&self) -> Result<Vec<Range<u64>>, HapiError> {
+		let (toc, len) = {
+			let mut reader = self.reader();
+			let start = reader.header.toc_offset as u64;
+			reader.seek(SeekFrom::Start(start))?;
+			let toc = if toc_is_compressed(&mut *reader)? {
+				CompressedToc::read(&mut reader, &*self.zlib)?;
+				start..reader.stream_position()?
+			} else {
+				// `toc_size` is where it ends, not how long it is
+				start..u64::from(reader.header.toc_size).max(start)
+			};
+			(toc, reader.seek(SeekFrom::End(0))?)
+		};
+		let mut used = vec![0..HAPI_HEADER_SIZE, toc];
+		self.collect_data_ranges(&self.root_dir, &mut used)?;
+
+		used.sort_by_key(|range| range.start);
+		let mut unused = Vec::new();
+		let mut pos = 0;
+		for range in used {
+			if range.start > pos {
+				unused.push(pos..range.start.min(len));
+			}
+			pos = pos.max(range.end);
+			if pos >= len {
+				break;
+			}
+		}
+		if pos < len {
+			unused.push(pos..len);
+		}
+		unused.retain(|range| !range.is_empty());
+
+		Ok(unused)
+	}
+
+	fn collect_data_ranges(
+		&self,
+		dir: &HapiDirectory,
+		used: &mut Vec<Range<u64>>,
+	) -> Result<(), HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			match entry {
+				HapiEntry::File(file) => used.push(self.file_data_range(file)?),
+				HapiEntry::Directory(dir) => self.collect_data_ranges(dir, used)?,
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Computes a value identifying the archive, for keying caches of its contents.
+	///
+	/// This hashes the header, then the path, offset, size and compression of every entry,
+	/// and the checksum of every compressed chunk. Only the table of contents and the
+	/// chunk headers are read, so it's much quicker than hashing the whole archive, and
+	/// changing a compressed file changes its chunks' checksums and so the fingerprint.
+	/// Uncompressed files have no checksums, though, so changes to their contents that
+	/// keep the same size go unnoticed. The hash (64-bit FNV-1a) doesn't depend on the
+	/// platform or Rust version, so fingerprints can be stored and compared later.
+	pub fn fingerprint(&self) -> Result<u64, HapiError> {
+		let mut hash = Fnv64::new();
+		{
+			let reader = self.reader();
+			let header = &reader.header;
+			hash.write(&header.marker);
+			hash.write(&header.toc_size.to_le_bytes());
+			hash.write(&header.key.unwrap_or(0).to_le_bytes());
+			hash.write(&header.toc_offset.to_le_bytes());
+		}
+
+		self.fingerprint_dir(&self.root_dir, &mut hash)?;
+		Ok(hash.finish())
+	}
+
+	fn fingerprint_dir(&self, dir: &HapiDirectory, hash: &mut Fnv64) -> Result<(), HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			hash.write(entry.path_str().as_bytes());
+			// keeps one path running into the next from hashing the same as another split
+			hash.write(&[0]);
+
+			match entry {
+				HapiEntry::File(file) => {
+					hash.write(&file.contents_offset.to_le_bytes());
+					hash.write(&file.extracted_size.to_le_bytes());
+					hash.write(&[file.compression as u8]);
+
+					let ranges = self.chunk_ranges(file)?;
+					let mut reader = self.reader();
+					for range in ranges {
+						reader.seek(SeekFrom::Start(range.start))?;
+						let header: HapiChunkHeader = reader.read_le()?;
+						hash.write(&header.checksum.to_le_bytes());
+					}
+				}
+				HapiEntry::Directory(dir) => {
+					hash.write(&[HAPI_FLAG_DIR]);
+					self.fingerprint_dir(dir, hash)?;
+					// marks where the directory ends, so moving an entry out of it counts
+					hash.write(&[0xff]);
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// Returns where each of `file`'s chunks is, from the table of their sizes at the
+	// start of its contents. Files without compression have none.
+	pub(super) fn chunk_ranges(&self, file: &HapiFile) -> Result<Vec<Range<u64>>, HapiError> {
+		if file.compression == HapiCompressionType::None || file.extracted_size == 0 {
+			return Ok(Vec::new());
+		}
+
+		let mut reader = self.reader();
+		let count = file.extracted_size.div_ceil(HAPI_CHUNK_SIZE) as usize;
+		reader.seek(SeekFrom::Start(file.contents_offset as u64))?;
+		let sizes: Vec<u32> = reader.read_le_args(VecArgs { count, inner: () })?;
+
+		// chunks follow right after the table of their sizes
+		let mut offset = file.contents_offset as u64 + count as u64 * 4;
+		Ok(sizes
+			.into_iter()
+			.map(|size| {
+				let start = offset;
+				offset += size as u64;
+				start..offset
+			})
+			.collect())
+	}
+}
+
+/// Decompresses a single compressed chunk on its own, outside of any archive.
+///
+/// `bytes` must start with the chunk's header (beginning with `SQSH`), followed by its
+/// data; anything after that is ignored. The checksum is checked, and the chunk's own
+/// cipher undone if it has one, but an archive-wide cipher must already have been.
+/// Zlib-compressed chunks are decompressed with [`Libflate`]. Like when extracting,
+/// the result isn't required to be the size the header says.
+///
+/// # Examples
+/// ```no_run
+/// use std::fs;
+///
+/// // a chunk copied out of an archive with a hex editor
+/// let chunk = fs::read("chunk.bin")?;
+/// let contents = hapi::decompress_chunk(&chunk)?;
+/// fs::write("chunk.out", contents)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn decompress_chunk(bytes: &[u8]) -> Result<Vec<u8>, HapiError> {
+	let chunk = HapiCompressedChunk::read_args(&mut io::Cursor::new(bytes), (true,))?;
+
+	let mut contents = Vec::new();
+	chunk.decode(&Libflate, &Unpooled, &mut contents)?;
+	Ok(contents)
+}
+
+// Just the header of a compressed chunk, for when its data isn't needed
+#[derive(BinRead)]
+#[br(little, magic = b"SQSH")]
+struct HapiChunkHeader {
+	_version_maybe: u8,
+	_compression: u8,
+	_is_enciphered: u8,
+	_compressed_size: u32,
+	_decompressed_size: u32,
+	checksum: u32,
+}
+
+// 64-bit FNV-1a, which (unlike `DefaultHasher`) is guaranteed to give the same result
+// everywhere
+struct Fnv64(u64);
+
+impl Fnv64 {
+	const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+	const PRIME: u64 = 0x0100_0000_01b3;
+
+	fn new() -> Self {
+		Fnv64(Self::OFFSET_BASIS)
+	}
+
+	fn write(&mut self, bytes: &[u8]) {
+		for &byte in bytes {
+			self.0 = (self.0 ^ byte as u64).wrapping_mul(Self::PRIME);
+		}
+	}
+
+	fn finish(&self) -> u64 {
+		self.0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	#[test]
+	fn mixed_chunks_reassemble() {
+		use HapiCompressionType::{Lz77, Zlib};
+
+		let contents = pattern(5 * HAPI_CHUNK_SIZE as usize / 2);
+		let data = Fixture::new(vec![mixed_file(
+			"mixed.gaf",
+			contents.clone(),
+			&[Lz77, Zlib],
+		)])
+		.build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let file = archive.get_file("mixed.gaf").unwrap();
+		assert_eq!(file.compression, Lz77);
+
+		let chunks = archive.chunks(file).unwrap();
+		let schemes: Vec<_> = chunks.iter().map(|chunk| chunk.compression).collect();
+		assert_eq!(schemes, [Lz77, Zlib, Lz77]);
+		assert!(chunks.iter().all(|chunk| chunk.checksum_ok));
+
+		assert_eq!(archive.read_file(file).unwrap(), contents);
+		let mut parallel = Vec::new();
+		archive
+			.write_file_inner(
+				file,
+				&mut parallel,
+				&mut ExtractOptions::new().decode_threads(3),
+			)
+			.unwrap();
+		assert_eq!(parallel, contents);
+	}
+
+	#[test]
+	fn unused_ranges_finds_gap_after_toc() {
+		let data = Fixture::new(sample())
+			.gap_after_toc(100)
+			.trailing(&[0; 7])
+			.build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let toc_end = u32::from_le_bytes(data[8..12].try_into().unwrap()) as u64;
+		let len = data.len() as u64;
+		assert_eq!(
+			archive.unused_ranges().unwrap(),
+			[toc_end..toc_end + 100, len - 7..len]
+		);
+
+		let data = Fixture::new(sample()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert!(archive.unused_ranges().unwrap().is_empty());
+	}
+}
@@ -1,11 +1,10 @@
 use crate::hapi::*;
 
-use std::error::Error;
+use std::fmt;
 use std::io::{self, prelude::*};
 use std::iter::Peekable;
 use std::ops::Range;
-
-use libflate::zlib;
+use std::thread;
 
 const HAPI_LZ77_WINDOW_SIZE: usize = 4095; // 2^12 - 1
 
@@ -23,7 +22,8 @@ impl<'a> HapiChunkDecoder<'a> {
 
 impl Read for HapiChunkDecoder<'_> {
 	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-		let bytes_count = (&self.source.data[self.cur_pos..]).read(buf)?;
+		let mut remaining = &self.source.data[self.cur_pos..];
+		let bytes_count = remaining.read(buf)?;
 
 		if self.source.is_enciphered {
 			// we have to fuckin
@@ -41,86 +41,216 @@ impl Read for HapiChunkDecoder<'_> {
 }
 
 impl HapiCompressedChunk {
-	pub(super) fn decompress<W: Write>(&self, output: &mut W) -> Result<(), Box<dyn Error>> {
-		let data = HapiChunkDecoder::new(self);
+	// Whether the stored checksum matches the (still compressed) data
+	pub(super) fn checksum_ok(&self) -> bool {
+		chunk_checksum(&self.data) == self.checksum
+	}
 
-		let real_size = match self.compression {
-			HapiCompressionType::None => {
-				unreachable!("chunk with HapiCompressionType::None passed to decompress()")
-			}
-			HapiCompressionType::Lz77 => self.decode_lz77(data, output)?,
-			HapiCompressionType::Zlib => io::copy(&mut zlib::Decoder::new(data)?, output)?,
-		};
+	// `file` is only used to say which file a chunk belongs to in warnings
+	pub(super) fn decompress<W: Write>(
+		&self,
+		file: &HapiFile,
+		zlib: &dyn ZlibBackend,
+		pool: &dyn BufferPool,
+		output: &mut W,
+	) -> Result<u64, HapiError> {
+		let real_size = self.decode(zlib, pool, output)?;
 
 		if real_size != self.decompressed_size as u64 {
 			eprintln!(
-				"Warning: chunk had inaccurate decompressed size (given {}, actual {}). \
+				"Warning: chunk of {} had inaccurate decompressed size (given {}, actual {}). \
 						Archive may be corrupt.",
-				self.decompressed_size, real_size
+				file.path_str(),
+				self.decompressed_size,
+				real_size
 			);
 		}
 
-		Ok(())
+		Ok(real_size)
+	}
+
+	// How much room to make for the chunk once it's decompressed. Its declared size
+	// can't be trusted not to be huge, but chunks are never meant to be over 64 KiB
+	fn capacity_hint(&self) -> usize {
+		self.decompressed_size.min(HAPI_CHUNK_SIZE) as usize
+	}
+
+	// Like `decompress`, minus the warning about the chunk's size
+	pub(super) fn decode<W: Write>(
+		&self,
+		zlib: &dyn ZlibBackend,
+		pool: &dyn BufferPool,
+		output: &mut W,
+	) -> Result<u64, HapiError> {
+		let mut data = HapiChunkDecoder::new(self);
+
+		Ok(match self.compression {
+			HapiCompressionType::None => {
+				unreachable!("chunk with HapiCompressionType::None passed to decompress()")
+			}
+			HapiCompressionType::Lz77 => self.decode_lz77(data, pool, output)?,
+			HapiCompressionType::Zlib => zlib.decode(&mut data, output)?,
+		})
+	}
+
+	// Decompresses `chunks` in batches of `threads`, then writes each batch out in order,
+	// calling `on_chunk` with the size of each one as it's written
+	#[allow(clippy::too_many_arguments)] // everything write_file_inner decompresses with
+	pub(super) fn decompress_parallel<W: Write>(
+		chunks: &[HapiCompressedChunk],
+		file: &HapiFile,
+		zlib: &dyn ZlibBackend,
+		pool: &dyn BufferPool,
+		output: &mut W,
+		threads: usize,
+		limit: u64,
+		mut on_chunk: impl FnMut(u64),
+	) -> Result<u64, HapiError> {
+		let mut written = 0;
+
+		for batch in chunks.chunks(threads) {
+			let decompressed = thread::scope(|scope| {
+				let handles: Vec<_> = batch
+					.iter()
+					.map(|chunk| {
+						scope.spawn(move || {
+							// keep a single chunk from blowing past the limit on its own
+							let mut buf = pool.take(chunk.capacity_hint());
+							chunk
+								.decompress(
+									file,
+									zlib,
+									pool,
+									&mut LimitedWriter::new(&mut buf, limit),
+								)
+								.map(|_| buf)
+						})
+					})
+					.collect();
+
+				handles
+					.into_iter()
+					.map(|handle| {
+						handle
+							.join()
+							.unwrap_or_else(|e| std::panic::resume_unwind(e))
+					})
+					.collect::<Vec<_>>()
+			});
+
+			for data in decompressed {
+				let data = data?;
+				output.write_all(&data)?;
+				written += data.len() as u64;
+				on_chunk(data.len() as u64);
+				pool.give(data);
+			}
+		}
+
+		Ok(written)
+	}
+
+	fn decode_lz77<W: Write>(
+		&self,
+		input: HapiChunkDecoder<'_>,
+		pool: &dyn BufferPool,
+		output: &mut W,
+	) -> io::Result<u64> {
+		let mut buffer = pool.take(2 * (HAPI_LZ77_WINDOW_SIZE + 1));
+		let mut window = pool.take(HAPI_LZ77_WINDOW_SIZE + 1);
+		window.resize(HAPI_LZ77_WINDOW_SIZE + 1, 0);
+
+		let result = Self::lz77(input, output, &mut buffer, &mut window);
+		pool.give(buffer);
+		pool.give(window);
+		result
 	}
 
-	fn decode_lz77<W: Write>(&self, input: HapiChunkDecoder<'_>, output: &mut W) -> io::Result<u64> {
+	// Decodes LZ77 data using the given (empty) buffer, and a window that's exactly the
+	// size of the LZ77 window
+	fn lz77<W: Write>(
+		input: HapiChunkDecoder<'_>,
+		output: &mut W,
+		buffer: &mut Vec<u8>,
+		window: &mut [u8],
+	) -> io::Result<u64> {
 		let decoder_unexpected_eof = Err(io::Error::new(
 			io::ErrorKind::UnexpectedEof,
 			"LZ77 decoding ended prematurely",
 		));
 
-		let mut buffer = Vec::<u8>::with_capacity(self.decompressed_size as usize);
-		let mut window = Vec::<u8>::with_capacity(HAPI_LZ77_WINDOW_SIZE as usize + 1);
-		window.resize_with(HAPI_LZ77_WINDOW_SIZE as usize + 1, Default::default);
+		// Decoded data is passed on to `output` a window's worth at a time, rather than
+		// held until the end of the chunk, which might claim to be any size at all
+		let mut written = 0;
 		let mut window_iter = (0..window.len()).peekable();
 
+		#[allow(clippy::unbuffered_bytes)] // the decoder reads from an in-memory chunk
 		let mut input = input.bytes();
 		loop {
 			if let Some(tag) = input.next() {
 				// TODO detect infinite loop
 				let tag = tag?;
+				if buffer.len() > HAPI_LZ77_WINDOW_SIZE {
+					output.write_all(buffer)?;
+					written += buffer.len() as u64;
+					buffer.clear();
+				}
 				for bit in 0..=7 {
-					if window_iter.len() == 0 {
-						// flush contents
-						buffer.extend_from_slice(&window);
-						window_iter = (0..window.len()).peekable();
-					}
+					// where the next byte goes in the window
+					let dest = match window_iter.peek() {
+						Some(&dest) => dest,
+						None => {
+							// flush contents
+							buffer.extend_from_slice(window);
+							window_iter = (0..window.len()).peekable();
+							0
+						}
+					};
 
 					if tag & (1 << bit) == 0 {
 						match input.next() {
 							Some(Err(e)) => return Err(e),
-							Some(Ok(lit)) => window[window_iter.next().unwrap()] = lit,
+							Some(Ok(lit)) => {
+								window[dest] = lit;
+								window_iter.next();
+							}
 							None => return decoder_unexpected_eof,
 						}
 					} else {
-						let lo = input.next().unwrap()? as u16;
-						let hi = input.next().unwrap()? as u16;
+						let (Some(lo), Some(hi)) = (input.next(), input.next()) else {
+							return decoder_unexpected_eof;
+						};
+						let (lo, hi) = (lo? as u16, hi? as u16);
 						let offset = (((hi << 8) | lo) >> 4) as usize;
 						if offset != 0 {
 							let offset = offset - 1; // now it's an array index
 							let count = ((lo & 0x0f) + 2) as usize;
-							if (offset..offset + count).contains(window_iter.peek().unwrap()) {
-								// this pointer copy reads what it's writing
+							let after_wrap = (offset + count) & HAPI_LZ77_WINDOW_SIZE;
+							let wraps = offset + count > HAPI_LZ77_WINDOW_SIZE;
+							if (offset..offset + count).contains(&dest)
+								|| (wraps && (dest < after_wrap || dest + count > window.len()))
+							{
+								// this pointer copy reads what it's writing, or both ends
+								// wrap around the window at once
 								// no choice but to copy a byte at a time...... hHHrhgHgh
 								Self::lz77_pointer_naive_push(
-									&mut buffer,
-									&mut window,
+									buffer,
+									window,
 									&mut window_iter,
+									dest,
 									offset,
 									count,
 								);
-							} else if offset + count > HAPI_LZ77_WINDOW_SIZE {
+							} else if wraps {
 								let window_len = window.len();
-								let after_wrap = (offset + count) & HAPI_LZ77_WINDOW_SIZE;
 								let before_wrap = count - after_wrap;
-								let dest = *window_iter.peek().unwrap();
 								window.copy_within(offset..window_len, dest);
 								window.copy_within(0..after_wrap, dest + before_wrap);
 								// advance_by isn't stable so Oh Well
 								let _ = window_iter.nth(count - 1);
 							} else if count > window_iter.len() {
 								// flush unwritten window data
-								let data_len = *window_iter.peek().unwrap();
+								let data_len = dest;
 								let remaining_len = window_iter.len();
 								buffer.extend_from_slice(&window[..data_len]);
 								// write from pointed-to data
@@ -133,17 +263,16 @@ impl HapiCompressedChunk {
 									..window.len())
 									.peekable();
 							} else {
-								let dest = *window_iter.peek().unwrap();
 								window.copy_within(offset..offset + count, dest);
 								// advance_by isn't stable so Oh Well
 								let _ = window_iter.nth(count - 1);
 							}
 						} else {
 							// flush unwritten window data to buffer, write all and done
-							let data_len = (HAPI_LZ77_WINDOW_SIZE as usize + 1) - window_iter.len();
+							let data_len = (HAPI_LZ77_WINDOW_SIZE + 1) - window_iter.len();
 							buffer.extend_from_slice(&window[..data_len]);
-							output.write_all(&buffer)?;
-							return Ok(buffer.len() as u64);
+							output.write_all(buffer)?;
+							return Ok(written + buffer.len() as u64);
 						}
 					}
 				}
@@ -155,12 +284,12 @@ impl HapiCompressedChunk {
 
 	fn lz77_pointer_naive_push(
 		buffer: &mut Vec<u8>,
-		window: &mut Vec<u8>,
+		window: &mut [u8],
 		window_iter: &mut Peekable<Range<usize>>,
+		dest: usize,
 		copy_start: usize,
 		copy_count: usize,
 	) {
-		let dest = *window_iter.peek().unwrap();
 		let indexer = (copy_start..copy_start + copy_count).zip(dest..dest + copy_count);
 
 		for (idx_src, idx_dest) in indexer {
@@ -177,3 +306,142 @@ impl HapiCompressedChunk {
 		}
 	}
 }
+
+// Passes writes through to `inner` until `limit` bytes have been written, after which
+// writing fails with an error that `is_output_limit_error` recognizes
+#[derive(Debug)]
+pub(super) struct LimitedWriter<W> {
+	inner: W,
+	remaining: u64,
+}
+
+#[derive(Debug)]
+struct OutputLimitExceeded;
+
+impl fmt::Display for OutputLimitExceeded {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("output size limit exceeded")
+	}
+}
+
+impl std::error::Error for OutputLimitExceeded {}
+
+impl<W: Write> LimitedWriter<W> {
+	pub(super) fn new(inner: W, limit: u64) -> Self {
+		LimitedWriter {
+			inner,
+			remaining: limit,
+		}
+	}
+}
+
+// Whether `e` came from a `LimitedWriter` running out of room
+pub(super) fn is_output_limit_error(e: &io::Error) -> bool {
+	e.get_ref().is_some_and(|e| e.is::<OutputLimitExceeded>())
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.len() as u64 > self.remaining {
+			return Err(io::Error::other(OutputLimitExceeded));
+		}
+
+		let count = self.inner.write(buf)?;
+		self.remaining -= count as u64;
+		Ok(count)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	// The decompressor from the format description, byte by byte
+	fn reference_lz77(data: &[u8]) -> Option<Vec<u8>> {
+		let mut window = [0; 4096];
+		let mut pos = 1;
+		let mut output = Vec::new();
+		let mut input = data.iter().map(|&byte| byte as usize);
+		loop {
+			let tag = input.next()?;
+			for bit in 0..8 {
+				if tag & (1 << bit) == 0 {
+					let byte = input.next()? as u8;
+					output.push(byte);
+					window[pos] = byte;
+					pos = (pos + 1) & 0xfff;
+				} else {
+					let pair = input.next()? | (input.next()? << 8);
+					let mut from = pair >> 4;
+					if from == 0 {
+						return Some(output);
+					}
+					for _ in 0..(pair & 0x0f) + 2 {
+						let byte = window[from];
+						output.push(byte);
+						window[pos] = byte;
+						from = (from + 1) & 0xfff;
+						pos = (pos + 1) & 0xfff;
+					}
+				}
+			}
+		}
+	}
+
+	// Random LZ77 data, pointing all over the window, that decodes to at least `len` bytes
+	fn random_lz77(seed: u32, len: usize) -> Vec<u8> {
+		let mut state = seed;
+		let mut next = move || {
+			state ^= state << 13;
+			state ^= state >> 17;
+			state ^= state << 5;
+			state
+		};
+
+		let mut data = Vec::new();
+		let mut decoded = 0;
+		while decoded < len {
+			let tag = next() as u8;
+			data.push(tag);
+			for bit in 0..8 {
+				if tag & (1 << bit) == 0 {
+					data.push(next() as u8);
+					decoded += 1;
+				} else {
+					let from = next() % 4095 + 1;
+					let count = next() % 16;
+					data.extend_from_slice(&((from << 4 | count) as u16).to_le_bytes());
+					decoded += count as usize + 2;
+				}
+			}
+		}
+		data.extend_from_slice(&[0xff, 0, 0]);
+		data
+	}
+
+	#[test]
+	fn lz77_matches_reference() {
+		for seed in 1..=50 {
+			let data = random_lz77(seed, 3 * HAPI_LZ77_WINDOW_SIZE);
+			let expected = reference_lz77(&data).unwrap();
+			let chunk = chunk_with_data(HapiCompressionType::Lz77, expected.len() as u32, &data);
+			assert_eq!(decompress_chunk(&chunk).unwrap(), expected, "seed {seed}");
+		}
+	}
+
+	#[test]
+	fn truncated_lz77_is_an_error() {
+		assert!(decompress_chunk(&truncated_lz77_chunk()).is_err());
+
+		let data = random_lz77(7, 2 * HAPI_LZ77_WINDOW_SIZE);
+		for len in (0..data.len() - 3).step_by(5) {
+			let chunk = chunk_with_data(HapiCompressionType::Lz77, 0, &data[..len]);
+			assert!(decompress_chunk(&chunk).is_err(), "cut to {len} bytes");
+		}
+	}
+}
@@ -0,0 +1,231 @@
+use super::*;
+
+/// A streaming reader over the decompressed contents of a single [`HapiFile`].
+///
+/// Returned by [`HapiArchive::open_file`]. Compressed files are decompressed one
+/// 64 KiB chunk at a time as they're read, so only one chunk is held in memory.
+///
+/// Seeking is supported. Since a chunk can only be decompressed from its start,
+/// seeking into a different chunk decompresses that chunk again on the next read;
+/// seeking around within the current chunk is free.
+#[derive(Debug)]
+pub struct HapiFileReader<'a, R: Read + Seek> {
+	archive: &'a HapiArchive<R>,
+	file: &'a HapiFile,
+	// absolute offset of each compressed chunk, empty if uncompressed
+	chunk_offsets: Vec<u64>,
+	// index and decompressed contents of the most recently read chunk
+	chunk: Option<(usize, Vec<u8>)>,
+	pos: u64,
+}
+
+impl<'a, R> HapiFileReader<'a, R>
+where
+	R: Read + Seek,
+{
+	pub(super) fn new(archive: &'a HapiArchive<R>, file: &'a HapiFile) -> Result<Self, HapiError> {
+		let chunk_offsets = archive
+			.chunk_ranges(file)?
+			.into_iter()
+			.map(|range| range.start)
+			.collect();
+
+		Ok(HapiFileReader {
+			archive,
+			file,
+			chunk_offsets,
+			chunk: None,
+			pos: 0,
+		})
+	}
+
+	/// Returns the file being read.
+	pub fn file(&self) -> &'a HapiFile {
+		self.file
+	}
+
+	// Makes sure the chunk at `index` is decompressed and returns its contents
+	fn load_chunk(&mut self, index: usize) -> Result<&[u8], HapiError> {
+		if !matches!(self.chunk, Some((i, _)) if i == index) {
+			let chunk = {
+				let mut reader = self.archive.reader();
+				reader.seek(SeekFrom::Start(self.chunk_offsets[index]))?;
+				HapiCompressedChunk::read_args(&mut *reader, (true,))?
+			};
+
+			let pool = &*self.archive.pool;
+			let mut data = pool.take(HAPI_CHUNK_SIZE as usize);
+			chunk.decompress(self.file, &*self.archive.zlib, pool, &mut data)?;
+
+			// Positions are worked out assuming every chunk but the last is full, so one
+			// that isn't would throw off everything after it
+			let expected = (self.file.extracted_size as u64 - index as u64 * HAPI_CHUNK_SIZE as u64)
+				.min(HAPI_CHUNK_SIZE as u64) as usize;
+			if data.len() != expected {
+				let len = data.len();
+				pool.give(data);
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!(
+						"chunk {} of {} decompressed to {} bytes instead of {}",
+						index,
+						self.file.path_str(),
+						len,
+						expected
+					),
+				)
+				.into());
+			}
+
+			if let Some((_, old)) = self.chunk.replace((index, data)) {
+				pool.give(old);
+			}
+		}
+
+		Ok(&self.chunk.as_ref().unwrap().1)
+	}
+}
+
+impl<R> Read for HapiFileReader<'_, R>
+where
+	R: Read + Seek,
+{
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let size = self.file.extracted_size as u64;
+		if self.pos >= size || buf.is_empty() {
+			return Ok(0);
+		}
+		let remaining = (size - self.pos).min(buf.len() as u64) as usize;
+
+		let count = if self.chunk_offsets.is_empty() {
+			let mut reader = self.archive.reader();
+			reader.seek(SeekFrom::Start(self.file.contents_offset as u64 + self.pos))?;
+			match reader.read(&mut buf[..remaining])? {
+				0 => {
+					return Err(io::Error::new(
+						io::ErrorKind::UnexpectedEof,
+						format!(
+							"{} ends {} bytes short of its size",
+							self.file.path_str(),
+							size - self.pos
+						),
+					));
+				}
+				count => count,
+			}
+		} else {
+			let index = (self.pos / HAPI_CHUNK_SIZE as u64) as usize;
+			let start = (self.pos % HAPI_CHUNK_SIZE as u64) as usize;
+			if index >= self.chunk_offsets.len() {
+				return Ok(0);
+			}
+
+			let data = self.load_chunk(index)?;
+			let available = data.get(start..).unwrap_or_default();
+			let count = available.len().min(remaining);
+			buf[..count].copy_from_slice(&available[..count]);
+			count
+		};
+
+		self.pos += count as u64;
+		Ok(count)
+	}
+}
+
+impl<R> Drop for HapiFileReader<'_, R>
+where
+	R: Read + Seek,
+{
+	fn drop(&mut self) {
+		if let Some((_, data)) = self.chunk.take() {
+			self.archive.pool.give(data);
+		}
+	}
+}
+
+impl<R> Seek for HapiFileReader<'_, R>
+where
+	R: Read + Seek,
+{
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => (self.file.extracted_size as u64).checked_add_signed(offset),
+			SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+		};
+
+		// Decompression happens lazily on the next read
+		self.pos = new_pos.ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"invalid seek to a negative or overflowing position",
+			)
+		})?;
+		Ok(self.pos)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	// An archive holding `short.txt`, whose record claims 10 more bytes than its one
+	// chunk decompresses to, and `whole.txt`, which is fine
+	fn archive_with_short_chunk() -> Vec<u8> {
+		let mut data = Fixture::new(vec![
+			file("short.txt", pattern(1000)),
+			file("whole.txt", pattern(1000)),
+		])
+		.build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let record = archive.get_file("short.txt").unwrap().entry_offset() as usize + 4;
+		data[record..record + 4].copy_from_slice(&1010u32.to_le_bytes());
+		data
+	}
+
+	#[test]
+	fn short_chunk_is_an_error() {
+		let data = archive_with_short_chunk();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+
+		let short = archive.get_file("short.txt").unwrap();
+		let mut contents = Vec::new();
+		let err = archive
+			.open_file(short)
+			.unwrap()
+			.read_to_end(&mut contents)
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		assert!(archive.peek_file(short, 4).is_err());
+
+		let whole = archive.get_file("whole.txt").unwrap();
+		let mut contents = Vec::new();
+		archive
+			.open_file(whole)
+			.unwrap()
+			.read_to_end(&mut contents)
+			.unwrap();
+		assert_eq!(contents, pattern(1000));
+		assert_eq!(archive.peek_file(whole, 4).unwrap(), pattern(4));
+	}
+
+	#[test]
+	fn short_uncompressed_file_is_an_error() {
+		let mut data = Fixture::new(vec![raw_file("raw.txt", pattern(100))]).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let record = archive.get_file("raw.txt").unwrap().entry_offset() as usize + 4;
+		data[record..record + 4].copy_from_slice(&110u32.to_le_bytes());
+
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let raw = archive.get_file("raw.txt").unwrap();
+		let mut contents = Vec::new();
+		let err = archive
+			.open_file(raw)
+			.unwrap()
+			.read_to_end(&mut contents)
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+		assert_eq!(contents, pattern(100));
+	}
+}
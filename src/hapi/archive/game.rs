@@ -0,0 +1,54 @@
+use super::*;
+
+use std::path::Component;
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Returns every unit definition (`.fbi` file) under `units/`.
+	pub fn units(&self) -> impl Iterator<Item = &HapiFile> {
+		self.files_under("units", "fbi")
+	}
+
+	/// Returns every weapon definition (`.tdf` file) under `weapons/`.
+	pub fn weapons(&self) -> impl Iterator<Item = &HapiFile> {
+		self.files_under("weapons", "tdf")
+	}
+
+	/// Returns every feature definition (`.tdf` file) under `features/`, including the
+	/// per-world subdirectories.
+	pub fn features(&self) -> impl Iterator<Item = &HapiFile> {
+		self.files_under("features", "tdf")
+	}
+
+	/// Returns every sound (`.wav` file) under `sounds/`.
+	pub fn sounds(&self) -> impl Iterator<Item = &HapiFile> {
+		self.files_under("sounds", "wav")
+	}
+
+	/// Returns every animation (`.gaf` file) under `anims/`.
+	pub fn anims(&self) -> impl Iterator<Item = &HapiFile> {
+		self.files_under("anims", "gaf")
+	}
+
+	// Files anywhere under the top-level directory `dir` with extension `ext`. TA itself
+	// doesn't care about case, so neither does this
+	fn files_under<'a>(&'a self, dir: &'a str, ext: &'a str) -> impl Iterator<Item = &'a HapiFile> {
+		self.walk()
+			.filter_map(HapiEntry::as_file)
+			.filter(move |file| {
+				let top = file
+					.path()
+					.components()
+					.find(|c| !matches!(c, Component::CurDir));
+				let in_dir = matches!(top, Some(Component::Normal(name))
+				if name.to_str().is_some_and(|name| name.eq_ignore_ascii_case(dir)));
+
+				in_dir
+					&& file
+						.extension()
+						.is_some_and(|e| e.eq_ignore_ascii_case(ext))
+			})
+	}
+}
@@ -0,0 +1,31 @@
+use super::*;
+
+use std::io::{BufReader, Cursor};
+
+use libflate::gzip::MultiDecoder;
+
+impl HapiArchive<Cursor<Vec<u8>>> {
+	/// Opens a gzip-compressed archive (like `Example.ufo.gz`) from the file at `path`.
+	///
+	/// Archives have to be seekable to be read, and gzip streams aren't, so the whole
+	/// archive is decompressed into memory before it's parsed. That means this needs as
+	/// much memory as the uncompressed archive takes up on disk; for big archives,
+	/// decompressing to a file and opening that with [`open`](Self::open) may be better.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	///
+	/// let archive = HapiArchive::open_gz("Example.ufo.gz")?;
+	/// archive.extract_all("Example")?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn open_gz(path: impl AsRef<Path>) -> Result<Self, HapiError> {
+		let file = BufReader::new(File::open(path)?);
+
+		let mut contents = Vec::new();
+		MultiDecoder::new(file)?.read_to_end(&mut contents)?;
+
+		Self::open(Cursor::new(contents))
+	}
+}
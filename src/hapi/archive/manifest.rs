@@ -0,0 +1,275 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::path::Component;
+
+use crc32fast::Hasher;
+
+// One line of a manifest: an extracted file, its size and the CRC-32 of its contents
+#[derive(Debug)]
+pub(super) struct ManifestEntry {
+	path: String,
+	size: u64,
+	crc: u32,
+}
+
+// A writer that keeps a CRC-32 of everything written through it, if asked to
+pub(super) struct CrcWriter<W> {
+	inner: W,
+	hasher: Option<Hasher>,
+}
+
+impl<W: Write> CrcWriter<W> {
+	pub(super) fn new(inner: W, hash: bool) -> Self {
+		CrcWriter {
+			inner,
+			hasher: hash.then(Hasher::new),
+		}
+	}
+
+	// Returns the inner writer, and the CRC-32 of what was written if there is one
+	pub(super) fn finish(self) -> (W, Option<u32>) {
+		(self.inner, self.hasher.map(Hasher::finalize))
+	}
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let count = self.inner.write(buf)?;
+		if let Some(hasher) = &mut self.hasher {
+			hasher.update(&buf[..count]);
+		}
+		Ok(count)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl ExtractOptions<'_> {
+	// Adds an extracted file to the manifest, if one is being written
+	pub(super) fn record(&mut self, file: &HapiFile, size: u64, crc: u32) {
+		if self.manifest_path.is_none() {
+			return;
+		}
+
+		self.manifest.push(ManifestEntry {
+			path: manifest_path(file),
+			size,
+			crc,
+		});
+	}
+
+	// Writes out the manifest, if there is one
+	pub(super) fn finish_manifest(&mut self) -> Result<(), HapiError> {
+		let Some(path) = &self.manifest_path else {
+			return Ok(());
+		};
+
+		let mut out = io::BufWriter::new(File::create(path)?);
+		writeln!(out, "# path\tsize\tcrc32")?;
+		for entry in self.manifest.drain(..) {
+			writeln!(out, "{}\t{}\t{:08x}", entry.path, entry.size, entry.crc)?;
+		}
+		out.flush()?;
+
+		Ok(())
+	}
+}
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Like [`read_file`](Self::read_file), but fails with [`HapiError::HashMismatch`]
+	/// unless the file's contents have the CRC-32 `expected`.
+	///
+	/// This is for checking files against a trusted list of hashes, like the manifests
+	/// written by [`ExtractOptions::write_manifest`].
+	pub fn read_file_checked(&self, entry: &HapiFile, expected: u32) -> Result<Vec<u8>, HapiError> {
+		let mut output = CrcWriter::new(Vec::new(), true);
+		self.write_file(entry, &mut output)?;
+
+		let (contents, crc) = output.finish();
+		check_crc(entry, expected, crc)?;
+		Ok(contents)
+	}
+
+	/// Like [`extract_file`](Self::extract_file), but fails with
+	/// [`HapiError::HashMismatch`] unless the file's contents have the CRC-32 `expected`.
+	///
+	/// The hash is worked out as the file is written, so the file is only read once. If
+	/// it doesn't match, the extracted file is deleted again before returning.
+	pub fn extract_file_checked(
+		&self,
+		entry: &HapiFile,
+		dest: impl AsRef<Path>,
+		expected: u32,
+	) -> Result<u64, HapiError> {
+		Self::check_dest(dest.as_ref(), false)?;
+
+		let filename = dest.as_ref().join(&*sanitize_name(entry.name()));
+		eprintln!("Creating file {}", filename.display());
+
+		let mut output = CrcWriter::new(File::create(&filename)?, true);
+		let result = self.write_file(entry, &mut output);
+		let (file, crc) = output.finish();
+		drop(file);
+
+		match result.and_then(|written| check_crc(entry, expected, crc).map(|_| written)) {
+			Err(e @ HapiError::HashMismatch { .. }) => {
+				fs::remove_file(&filename)?;
+				Err(e)
+			}
+			result => result,
+		}
+	}
+}
+
+/// A manifest written by [`ExtractOptions::write_manifest`], read back in.
+///
+/// It maps each file's path within the archive to its size and CRC-32 as extracted, for
+/// finding what's changed since with [`HapiArchive::changed_since`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+	entries: HashMap<String, (u64, u32)>,
+}
+
+impl Manifest {
+	/// Reads the manifest at `path`.
+	pub fn open(path: impl AsRef<Path>) -> Result<Manifest, HapiError> {
+		Self::read(io::BufReader::new(File::open(path)?))
+	}
+
+	/// Reads a manifest from `reader`.
+	///
+	/// Lines starting with `#` and blank lines are skipped. Any other line that isn't a
+	/// path, size and CRC-32 separated by tabs fails with an [`InvalidData`] error saying
+	/// which line it is.
+	///
+	/// [`InvalidData`]: io::ErrorKind::InvalidData
+	pub fn read(reader: impl BufRead) -> Result<Manifest, HapiError> {
+		let mut entries = HashMap::new();
+
+		for (number, line) in reader.lines().enumerate() {
+			let line = line?;
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			// paths can't have tabs in them, but split from the right to be safe
+			let mut fields = line.rsplitn(3, '\t');
+			let entry = match (fields.next(), fields.next(), fields.next()) {
+				(Some(crc), Some(size), Some(path)) => size
+					.parse()
+					.ok()
+					.zip(u32::from_str_radix(crc, 16).ok())
+					.map(|entry| (path.to_string(), entry)),
+				_ => None,
+			};
+			let Some((path, entry)) = entry else {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("Malformed manifest line {}: {}", number + 1, line),
+				)
+				.into());
+			};
+			entries.insert(path, entry);
+		}
+
+		Ok(Manifest { entries })
+	}
+
+	/// Returns the size and CRC-32 recorded for `path`, a path within the archive with
+	/// `/` separators and no leading `./` (like `units/ARMCOM.FBI`).
+	pub fn get(&self, path: &str) -> Option<(u64, u32)> {
+		self.entries.get(path).copied()
+	}
+
+	/// Returns the number of files in the manifest.
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Returns `true` if the manifest has no files in it.
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+}
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Returns the files in the archive that differ from what `manifest` records, in the
+	/// order of [`walk`](Self::walk).
+	///
+	/// A file is different if the manifest doesn't list it, or lists a different size
+	/// or CRC-32 for it. Files whose size matches have to be decompressed to work out
+	/// their CRC-32, so this costs about as much as extracting them (minus the
+	/// writing). Files listed in the manifest that aren't in the archive anymore aren't
+	/// reported. Like `walk`, this only sees loaded directories when the archive was
+	/// opened lazily.
+	///
+	/// Manifests record files as they were written, so one written with an
+	/// [`ExtractOptions::transform`] that changed some files will have those files
+	/// show up as changed.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+	/// let manifest = Manifest::open("Example.manifest")?;
+	/// for file in archive.changed_since(&manifest)? {
+	/// 	archive.extract_file(file, "Example")?;
+	/// }
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn changed_since(&self, manifest: &Manifest) -> Result<Vec<&HapiFile>, HapiError> {
+		let mut changed = Vec::new();
+
+		for file in self.walk().filter_map(HapiEntry::as_file) {
+			let unchanged = match manifest.get(&manifest_path(file)) {
+				Some((size, crc)) if size == file.extracted_size as u64 => {
+					let mut output = CrcWriter::new(io::sink(), true);
+					let written = self.write_file(file, &mut output)?;
+					written == size && output.finish().1 == Some(crc)
+				}
+				_ => false,
+			};
+
+			if !unchanged {
+				changed.push(file);
+			}
+		}
+
+		Ok(changed)
+	}
+}
+
+// How `file` is named in a manifest: separators are always `/`, whatever the platform,
+// and the `./` is dropped
+fn manifest_path(file: &HapiFile) -> String {
+	file.path()
+		.components()
+		.filter_map(|component| match component {
+			Component::Normal(name) => name.to_str(),
+			_ => None,
+		})
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+fn check_crc(entry: &HapiFile, expected: u32, actual: Option<u32>) -> Result<(), HapiError> {
+	match actual {
+		Some(actual) if actual != expected => Err(HapiError::HashMismatch {
+			path: entry.path.clone(),
+			expected,
+			actual,
+		}),
+		_ => Ok(()),
+	}
+}
@@ -0,0 +1,117 @@
+use super::*;
+
+/// Several streams read back to back as if they were one, for archives split into parts.
+///
+/// Created by [`HapiArchive::open_parts`], or by hand with [`MultiPart::new`]. Offsets
+/// are counted from the start of the first part, so everything that depends on them
+/// (like the archive's cipher) works across part boundaries.
+#[derive(Debug)]
+pub struct MultiPart<R> {
+	// each part, along with the offset it starts at in the whole
+	parts: Vec<(u64, R)>,
+	len: u64,
+	pos: u64,
+}
+
+impl<R> MultiPart<R>
+where
+	R: Read + Seek,
+{
+	/// Joins `parts`, in order. Each part is read from its start to its end, regardless
+	/// of its current position.
+	pub fn new(parts: Vec<R>) -> io::Result<Self> {
+		let mut len = 0;
+		let mut joined = Vec::with_capacity(parts.len());
+
+		for mut part in parts {
+			let part_len = part.seek(SeekFrom::End(0))?;
+			joined.push((len, part));
+			len += part_len;
+		}
+
+		Ok(MultiPart {
+			parts: joined,
+			len,
+			pos: 0,
+		})
+	}
+
+	/// Returns the parts, in order.
+	pub fn into_parts(self) -> Vec<R> {
+		self.parts.into_iter().map(|(_, part)| part).collect()
+	}
+}
+
+impl<R> Read for MultiPart<R>
+where
+	R: Read + Seek,
+{
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.pos >= self.len || buf.is_empty() {
+			return Ok(0);
+		}
+
+		// the last part starting at or before `pos`; empty parts get skipped over, since
+		// the part after them starts at the same offset
+		let index = self.parts.partition_point(|(start, _)| *start <= self.pos) - 1;
+		let next_start = self
+			.parts
+			.get(index + 1)
+			.map_or(self.len, |(start, _)| *start);
+
+		// Reads stop at the end of a part, like a short read from a single stream
+		let max = (next_start - self.pos).min(buf.len() as u64) as usize;
+		let (start, part) = &mut self.parts[index];
+		part.seek(SeekFrom::Start(self.pos - *start))?;
+		let count = part.read(&mut buf[..max])?;
+
+		self.pos += count as u64;
+		Ok(count)
+	}
+}
+
+impl<R> Seek for MultiPart<R>
+where
+	R: Read + Seek,
+{
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+			SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+		};
+
+		// Parts are only seeked when they're read from
+		self.pos = new_pos.ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"invalid seek to a negative or overflowing position",
+			)
+		})?;
+		Ok(self.pos)
+	}
+}
+
+impl<R> HapiArchive<MultiPart<R>>
+where
+	R: Read + Seek,
+{
+	/// Opens an archive that was split into several files, given each part in order.
+	///
+	/// The parts are read as one continuous stream (see [`MultiPart`]), so they don't
+	/// need to be joined on disk first.
+	///
+	/// # Examples
+	/// ```no_run
+	/// use hapi::prelude::*;
+	/// use std::fs::File;
+	///
+	/// let parts = vec![File::open("Example.ufo.1")?, File::open("Example.ufo.2")?];
+	/// let archive = HapiArchive::open_parts(parts)?;
+	/// archive.extract_all("Example")?;
+	/// # Ok::<(), Box<dyn std::error::Error>>(())
+	/// ```
+	pub fn open_parts(parts: Vec<R>) -> Result<Self, HapiError> {
+		Self::open(MultiPart::new(parts)?)
+	}
+}
@@ -0,0 +1,392 @@
+use super::*;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::time::SystemTime;
+
+/// What to do when a file being extracted already exists at its destination.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum ConflictPolicy {
+	/// Truncate and overwrite the existing file.
+	#[default]
+	Overwrite,
+	/// Leave the existing file alone and move on.
+	Skip,
+	/// Stop with an error.
+	Error,
+}
+
+/// What to do when an archive's header has a marker that isn't known.
+///
+/// Archives normally have the marker `00 00 01 00`; anything else (besides save data,
+/// which isn't supported) might be a variant of the format or might not be a proper
+/// archive at all.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum UnknownMarkerPolicy {
+	/// Open the archive as usual, without saying anything.
+	Proceed,
+	/// Open the archive as usual, noting the marker in
+	/// [`HapiArchive::warnings`].
+	#[default]
+	Warn,
+	/// Stop with [`HapiError::UnknownMarker`].
+	Error,
+}
+
+/// What to do when an archive's header says its table of contents ends before there's
+/// room for even an empty root directory.
+///
+/// An archive like that is corrupt, or something else that happens to start like one,
+/// so whatever gets parsed from it is likely to be garbage.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SmallTocPolicy {
+	/// Open the archive as usual, without saying anything.
+	Proceed,
+	/// Open the archive as usual, noting the size in [`HapiArchive::warnings`].
+	#[default]
+	Warn,
+	/// Stop with [`HapiError::CorruptHeader`].
+	Error,
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&HapiFile, &Path) + 'a>;
+type FileProgressCallback<'a> = Box<dyn FnMut(&HapiFile, u64, u64) + 'a>;
+type TransformCallback<'a> = Box<dyn FnMut(&HapiFile, &[u8]) -> Vec<u8> + 'a>;
+
+/// Settings for the `*_with` family of extraction methods on [`HapiArchive`].
+///
+/// `ExtractOptions::default()` (or [`new`](Self::new)) matches the behavior of the
+/// plain extraction methods; the builder methods tweak it from there.
+///
+/// # Examples
+/// ```no_run
+/// use hapi::prelude::*;
+/// use std::fs::File;
+///
+/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+/// let options = ExtractOptions::new()
+/// 	.on_conflict(ConflictPolicy::Skip)
+/// 	.continue_on_error(true)
+/// 	.progress(|file, _| eprintln!("{}", file.path_str()));
+/// archive.extract_all_with("Example", options)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct ExtractOptions<'a> {
+	pub(super) on_conflict: ConflictPolicy,
+	pub(super) verify_checksums: bool,
+	pub(super) sanitize_names: bool,
+	pub(super) continue_on_error: bool,
+	pub(super) decode_threads: usize,
+	pub(super) max_output_size: Option<u64>,
+	pub(super) check_file_size: bool,
+	pub(super) mtime: Option<SystemTime>,
+	pub(super) progress: Option<ProgressCallback<'a>>,
+	pub(super) file_progress: Option<FileProgressCallback<'a>>,
+	pub(super) transform: Option<TransformCallback<'a>>,
+	pub(super) manifest_path: Option<PathBuf>,
+	pub(super) manifest: Vec<ManifestEntry>,
+	pub(super) written: Option<Vec<PathBuf>>,
+}
+
+impl Default for ExtractOptions<'_> {
+	fn default() -> Self {
+		ExtractOptions {
+			on_conflict: ConflictPolicy::default(),
+			verify_checksums: true,
+			sanitize_names: true,
+			continue_on_error: false,
+			decode_threads: 1,
+			max_output_size: None,
+			check_file_size: false,
+			mtime: None,
+			progress: None,
+			file_progress: None,
+			transform: None,
+			manifest_path: None,
+			manifest: Vec::new(),
+			written: None,
+		}
+	}
+}
+
+impl Debug for ExtractOptions<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ExtractOptions")
+			.field("on_conflict", &self.on_conflict)
+			.field("verify_checksums", &self.verify_checksums)
+			.field("sanitize_names", &self.sanitize_names)
+			.field("continue_on_error", &self.continue_on_error)
+			.field("decode_threads", &self.decode_threads)
+			.field("max_output_size", &self.max_output_size)
+			.field("check_file_size", &self.check_file_size)
+			.field("mtime", &self.mtime)
+			.field("progress", &self.progress.as_ref().map(|_| ".."))
+			.field("file_progress", &self.file_progress.as_ref().map(|_| ".."))
+			.field("transform", &self.transform.as_ref().map(|_| ".."))
+			.field("manifest_path", &self.manifest_path)
+			.finish()
+	}
+}
+
+impl<'a> ExtractOptions<'a> {
+	/// Returns the default options.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sets what happens when a destination file already exists. Defaults to
+	/// [`ConflictPolicy::Overwrite`].
+	pub fn on_conflict(mut self, policy: ConflictPolicy) -> Self {
+		self.on_conflict = policy;
+		self
+	}
+
+	/// Sets whether compressed chunks have their checksums checked while reading.
+	/// Defaults to `true`.
+	pub fn verify_checksums(mut self, verify: bool) -> Self {
+		self.verify_checksums = verify;
+		self
+	}
+
+	/// Sets whether entry names are made safe before being used as file names.
+	/// Defaults to `true`.
+	///
+	/// Sanitizing replaces path separators and characters that aren't allowed in
+	/// file names with `_`, and renames entries called `.` or `..` (or nothing at all),
+	/// so nothing gets written outside of the destination directory.
+	pub fn sanitize_names(mut self, sanitize: bool) -> Self {
+		self.sanitize_names = sanitize;
+		self
+	}
+
+	/// Sets whether extraction carries on after a file fails to extract, printing a
+	/// warning instead of returning the error. Defaults to `false`.
+	pub fn continue_on_error(mut self, continue_on_error: bool) -> Self {
+		self.continue_on_error = continue_on_error;
+		self
+	}
+
+	/// Sets how many threads may decompress a single file's chunks at once. Defaults
+	/// to `1`, which decompresses everything on the calling thread.
+	///
+	/// Each 64 KiB chunk of a compressed file can be decompressed independently, so
+	/// large files benefit from spreading them across threads. Chunks are handled in
+	/// batches of `threads` and written out in order, so at most `threads` decompressed
+	/// chunks are held in memory at a time. Values below `1` are treated as `1`.
+	pub fn decode_threads(mut self, threads: usize) -> Self {
+		self.decode_threads = threads.max(1);
+		self
+	}
+
+	/// Sets the most bytes any single file may decompress to. Defaults to no limit.
+	///
+	/// A file that would decompress to more than `limit` bytes fails with
+	/// [`HapiError::OutputTooLarge`] as soon as it passes the limit, instead of going on
+	/// to fill the disk. This guards against decompression bombs in untrusted archives,
+	/// since a chunk's declared sizes don't bound how much it actually decompresses to.
+	pub fn max_output_size(mut self, limit: u64) -> Self {
+		self.max_output_size = Some(limit);
+		self
+	}
+
+	/// Sets whether each file's decompressed size must match its
+	/// [`extracted_size`](HapiFile::extracted_size). Defaults to `false`.
+	///
+	/// Each chunk only knows its own size, so a file missing a chunk (or with an extra
+	/// one) otherwise decompresses without complaint. With this set, such files fail
+	/// with [`HapiError::FileSizeMismatch`] once they've been written out.
+	pub fn check_file_size(mut self, check: bool) -> Self {
+		self.check_file_size = check;
+		self
+	}
+
+	/// Sets the modification time given to every extracted file. Defaults to `None`,
+	/// which leaves it as whenever the file was written.
+	///
+	/// HAPI archives don't record timestamps, so there's nothing to preserve; setting a
+	/// fixed time instead (like [`UNIX_EPOCH`](std::time::UNIX_EPOCH)) makes repeated
+	/// extractions identical, timestamps included. Only files are affected, not
+	/// directories.
+	pub fn set_mtime(mut self, mtime: Option<SystemTime>) -> Self {
+		self.mtime = mtime;
+		self
+	}
+
+	/// Sets a callback to run before each file is extracted. It receives the file and
+	/// the path it's about to be written to.
+	pub fn progress(mut self, callback: impl FnMut(&HapiFile, &Path) + 'a) -> Self {
+		self.progress = Some(Box::new(callback));
+		self
+	}
+
+	/// Sets a callback to run as each file is decompressed, for showing progress within
+	/// large files.
+	///
+	/// It receives the file, the number of bytes decompressed so far and the file's
+	/// [`extracted_size`](HapiFile::extracted_size), and runs after each 64 KiB chunk is
+	/// written out (or once, for uncompressed files). This complements
+	/// [`progress`](Self::progress), which only runs once per file.
+	pub fn file_progress(mut self, callback: impl FnMut(&HapiFile, u64, u64) + 'a) -> Self {
+		self.file_progress = Some(Box::new(callback));
+		self
+	}
+
+	/// Sets a callback that rewrites each file's contents before it's written out.
+	///
+	/// The callback receives the file and its decompressed contents, and returns what
+	/// should be written in their place. Note that this means each file is read into
+	/// memory in full before being written.
+	pub fn transform(mut self, callback: impl FnMut(&HapiFile, &[u8]) -> Vec<u8> + 'a) -> Self {
+		self.transform = Some(Box::new(callback));
+		self
+	}
+
+	/// Sets a file to write a manifest of everything extracted to. By default, no
+	/// manifest is written.
+	///
+	/// The manifest is a tab-separated list with a line for each extracted file: its
+	/// path within the archive (with `/` separators), its size, and the CRC-32 of its
+	/// contents in hex, as written out. It's written once extraction is done, so the
+	/// extracted tree can be checked against it later without the archive. The first
+	/// line is a header starting with `#`.
+	pub fn write_manifest(mut self, path: impl Into<PathBuf>) -> Self {
+		self.manifest_path = Some(path.into());
+		self
+	}
+
+	// Turns an entry name into something usable as a file name, if sanitizing is on
+	pub(super) fn file_name<'n>(&self, name: &'n str) -> Cow<'n, str> {
+		if self.sanitize_names {
+			sanitize_name(name)
+		} else {
+			Cow::Borrowed(name)
+		}
+	}
+}
+
+// Replaces anything in `name` that could escape the directory it's written to
+pub(super) fn sanitize_name(name: &str) -> Cow<'_, str> {
+	let is_bad = |c: char| matches!(c, '/' | '\\' | ':') || c.is_control();
+
+	match name {
+		"" | "." | ".." => Cow::Owned(name.replace('.', "_") + "_"),
+		_ if name.contains(is_bad) => Cow::Owned(name.replace(is_bad, "_")),
+		_ => Cow::Borrowed(name),
+	}
+}
+
+/// Settings for [`HapiArchive::open_with`].
+///
+/// `OpenOptions::default()` (or [`new`](Self::new)) opens archives exactly like
+/// [`HapiArchive::open`] does.
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+	pub(super) key: Option<Option<u32>>,
+	pub(super) lazy: bool,
+	pub(super) zlib: Option<Arc<dyn ZlibBackend>>,
+	pub(super) pool: Option<Arc<dyn BufferPool>>,
+	pub(super) names: NameEncoding,
+	pub(super) on_unknown_marker: UnknownMarkerPolicy,
+	pub(super) on_small_toc: SmallTocPolicy,
+	pub(super) max_depth: Option<usize>,
+}
+
+impl Default for OpenOptions {
+	fn default() -> Self {
+		OpenOptions {
+			key: None,
+			lazy: false,
+			zlib: None,
+			pool: None,
+			names: NameEncoding::default(),
+			on_unknown_marker: UnknownMarkerPolicy::default(),
+			on_small_toc: SmallTocPolicy::default(),
+			max_depth: Some(DEFAULT_MAX_DEPTH),
+		}
+	}
+}
+
+// Deeper than any real archive goes, but shallow enough to parse on a small thread stack
+const DEFAULT_MAX_DEPTH: usize = 64;
+
+impl OpenOptions {
+	/// Returns the default options.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Overrides the cipher key derived from the archive's header.
+	///
+	/// `Some(key)` deciphers the archive with `key` (the key as used by the cipher,
+	/// not as stored in the header), and `None` reads it as plain data. This is for
+	/// archives whose header lies about whether they're enciphered; normally the
+	/// key in the header is the right one.
+	pub fn key(mut self, key: Option<u32>) -> Self {
+		self.key = Some(key);
+		self
+	}
+
+	/// Sets whether to parse the directory tree one level at a time. Defaults to `false`.
+	///
+	/// Normally the whole tree is parsed when the archive is opened, which for a huge
+	/// archive means seeking to every single entry. With lazy parsing, only the root
+	/// directory's entries are read; subdirectories are left unloaded (they have no
+	/// entries, and [`HapiDirectory::is_loaded`] returns `false`) until they're passed
+	/// to [`HapiArchive::load_dir`].
+	///
+	/// Extraction, [`HapiArchive::sync_to_dir`] and [`HapiArchive::is_valid`] load
+	/// directories as they go. Other methods that walk the tree only see what's loaded.
+	///
+	/// This has no effect on archives whose table of contents is itself compressed,
+	/// which have to be parsed in full as soon as it's decompressed.
+	pub fn lazy(mut self, lazy: bool) -> Self {
+		self.lazy = lazy;
+		self
+	}
+
+	/// Sets how entry names are decoded. Defaults to [`NameEncoding::Utf8Lossy`].
+	pub fn name_encoding(mut self, encoding: NameEncoding) -> Self {
+		self.names = encoding;
+		self
+	}
+
+	/// Sets what happens when the header has an unknown marker. Defaults to
+	/// [`UnknownMarkerPolicy::Warn`].
+	pub fn on_unknown_marker(mut self, policy: UnknownMarkerPolicy) -> Self {
+		self.on_unknown_marker = policy;
+		self
+	}
+
+	/// Sets what happens when the header says the table of contents is too small to hold
+	/// the root directory. Defaults to [`SmallTocPolicy::Warn`].
+	pub fn on_small_toc(mut self, policy: SmallTocPolicy) -> Self {
+		self.on_small_toc = policy;
+		self
+	}
+
+	/// Sets how many levels deep the archive's entries may be nested. Defaults to
+	/// `Some(64)`; `None` means no limit.
+	///
+	/// Entries directly in the root directory are 1 level deep, like with
+	/// [`HapiArchive::max_depth`]. Parsing an entry nested deeper than `limit` fails with
+	/// [`HapiError::TooDeep`]. Directories are parsed recursively, so without a limit, a
+	/// crafted archive nesting thousands of directories overflows the stack (which
+	/// aborts the whole process) instead. With lazy parsing, the limit also applies as
+	/// directories are loaded.
+	pub fn max_depth(mut self, limit: Option<usize>) -> Self {
+		self.max_depth = limit;
+		self
+	}
+
+	/// Sets the decompressor used for zlib-compressed chunks. Defaults to [`Libflate`].
+	pub fn zlib_backend(mut self, backend: impl ZlibBackend + 'static) -> Self {
+		self.zlib = Some(Arc::new(backend));
+		self
+	}
+
+	/// Sets where buffers for decompressing chunks come from. Defaults to [`Unpooled`].
+	pub fn buffer_pool(mut self, pool: impl BufferPool + 'static) -> Self {
+		self.pool = Some(Arc::new(pool));
+		self
+	}
+}
@@ -0,0 +1,161 @@
+use super::*;
+
+use std::fmt;
+
+/// Where the working buffers for decompressing chunks come from.
+///
+/// Every compressed chunk needs somewhere to decompress to (up to 64 KiB), and LZ77
+/// chunks need a little more room to work in, so extracting lots of files makes lots
+/// of short-lived allocations. Implement this to reuse buffers instead, and pass it to
+/// [`OpenOptions::buffer_pool`]. The default is [`Unpooled`]; [`VecPool`] is a simple
+/// pool that's shared between threads.
+///
+/// Buffers that are in use when decompression fails may be dropped instead of being
+/// given back.
+pub trait BufferPool: Debug + Send + Sync {
+	/// Returns an empty buffer, preferably with room for at least `capacity` bytes.
+	fn take(&self, capacity: usize) -> Vec<u8>;
+
+	/// Gives back a buffer from [`take`](Self::take) that's no longer needed. Whatever
+	/// it still holds is garbage.
+	fn give(&self, buf: Vec<u8>);
+}
+
+impl<T: BufferPool + ?Sized> BufferPool for Arc<T> {
+	fn take(&self, capacity: usize) -> Vec<u8> {
+		(**self).take(capacity)
+	}
+
+	fn give(&self, buf: Vec<u8>) {
+		(**self).give(buf)
+	}
+}
+
+/// The default [`BufferPool`], which allocates a new buffer every time and drops the
+/// ones given back.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Unpooled;
+
+impl BufferPool for Unpooled {
+	fn take(&self, capacity: usize) -> Vec<u8> {
+		Vec::with_capacity(capacity)
+	}
+
+	fn give(&self, _buf: Vec<u8>) {}
+}
+
+/// A [`BufferPool`] that keeps up to a fixed number of buffers around for reuse.
+///
+/// Wrap it in an [`Arc`] to share it between archives.
+///
+/// # Examples
+/// ```no_run
+/// use hapi::prelude::*;
+/// use hapi::VecPool;
+/// use std::fs::File;
+/// use std::sync::Arc;
+///
+/// let pool = Arc::new(VecPool::new(64));
+/// for name in ["One.ufo", "Two.ufo"] {
+/// 	let options = OpenOptions::new().buffer_pool(pool.clone());
+/// 	HapiArchive::open_with(File::open(name)?, options)?.extract_all("Out")?;
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct VecPool {
+	bufs: Mutex<Vec<Vec<u8>>>,
+	max: usize,
+}
+
+impl VecPool {
+	/// Creates an empty pool that holds on to at most `max` buffers at a time.
+	pub fn new(max: usize) -> Self {
+		VecPool {
+			bufs: Mutex::new(Vec::new()),
+			max,
+		}
+	}
+
+	fn bufs(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+		// the buffers are just spare memory, so they can't be left in a bad state
+		self.bufs.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+impl Debug for VecPool {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		// the buffers' contents are garbage, so just say how many there are
+		f.debug_struct("VecPool")
+			.field("held", &self.bufs().len())
+			.field("max", &self.max)
+			.finish()
+	}
+}
+
+impl BufferPool for VecPool {
+	fn take(&self, capacity: usize) -> Vec<u8> {
+		let buf = self.bufs().pop();
+		match buf {
+			Some(mut buf) => {
+				buf.clear();
+				buf.reserve(capacity);
+				buf
+			}
+			None => Vec::with_capacity(capacity),
+		}
+	}
+
+	fn give(&self, buf: Vec<u8>) {
+		let mut bufs = self.bufs();
+		if bufs.len() < self.max {
+			bufs.push(buf);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn vec_pool_reuses_up_to_max() {
+		let pool = VecPool::new(2);
+		let mut first = pool.take(100);
+		first.extend_from_slice(b"garbage");
+		let ptr = first.as_ptr();
+		pool.give(first);
+
+		let reused = pool.take(50);
+		assert_eq!(reused.as_ptr(), ptr);
+		assert!(reused.is_empty());
+		assert!(reused.capacity() >= 100);
+		pool.give(reused);
+
+		for _ in 0..4 {
+			pool.give(Vec::with_capacity(10));
+		}
+		assert_eq!(pool.bufs().len(), 2);
+		let bufs = [pool.take(10), pool.take(10), pool.take(10)];
+		assert!(pool.bufs().is_empty());
+		assert!(bufs.iter().all(|buf| buf.capacity() >= 10));
+	}
+
+	#[test]
+	fn pooled_archive_reads_the_same() {
+		use crate::hapi::test_support::*;
+
+		let data = Fixture::new(sample()).build();
+		let pool = Arc::new(VecPool::new(4));
+		let options = OpenOptions::new().buffer_pool(pool.clone());
+		let pooled = HapiArchive::open_with(io::Cursor::new(&data), options).unwrap();
+		let plain = HapiArchive::open_slice(&data).unwrap();
+		for file in plain.walk().filter_map(HapiEntry::as_file) {
+			let pooled_file = pooled.get_file(file.path()).unwrap();
+			assert_eq!(
+				pooled.read_file(pooled_file).unwrap(),
+				plain.read_file(file).unwrap()
+			);
+		}
+		assert!(!pool.bufs().is_empty());
+	}
+}
@@ -0,0 +1,141 @@
+use super::*;
+
+// Offset of the checksum field within a chunk header
+const CHUNK_CHECKSUM_OFFSET: u64 = 15;
+// Offset of the stored key within the header
+const HEADER_KEY_OFFSET: u64 = 12;
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Writes a copy of the archive to `out` with every chunk checksum corrected.
+	///
+	/// Some packers wrote the wrong checksums for perfectly good chunks, which makes
+	/// those archives fail [`is_valid`](Self::is_valid) and checked extraction. This
+	/// copies the archive byte for byte, only rewriting the checksum field of chunks
+	/// whose stored checksum doesn't match their data; nothing is decompressed or
+	/// recompressed, so the data itself comes out bit-exact. Returns the number of
+	/// checksums that were fixed.
+	///
+	/// `out` should be empty, since the copy is written from its start.
+	pub fn fix_checksums(&self, mut out: impl Write + Seek) -> Result<u64, HapiError> {
+		self.copy_archive(&mut out)?;
+
+		let mut fixed = Vec::new();
+		self.find_bad_checksums(&self.root_dir, &mut fixed)?;
+
+		let reader = self.reader();
+		for &(offset, checksum) in &fixed {
+			let mut field = checksum.to_le_bytes();
+			reader.apply_cipher(offset, &mut field);
+			out.seek(SeekFrom::Start(offset))?;
+			out.write_all(&field)?;
+		}
+		out.flush()?;
+
+		Ok(fixed.len() as u64)
+	}
+
+	/// Writes a copy of the archive to `out`, enciphered with `new_key` instead of its
+	/// own key.
+	///
+	/// `new_key` is the key as used by the cipher, like [`OpenOptions::key`]; only its
+	/// low 8 bits matter, and the header gets the value from [`encode_key`]. `None`
+	/// writes the archive without a cipher at all. If the archive was opened with a key
+	/// override, that's the key it's deciphered with here too.
+	///
+	/// Everything besides the header's key field is copied byte for byte once
+	/// deciphered, so compressed data is never decompressed or recompressed. Chunks
+	/// that are enciphered on their own are left that way, since their cipher doesn't
+	/// depend on any key.
+	///
+	/// `out` should be empty, since the copy is written from its start.
+	pub fn rekey(&self, mut out: impl Write + Seek, new_key: Option<u32>) -> Result<(), HapiError> {
+		let mut reader = self.reader();
+		let toc_offset = reader.header.toc_offset;
+		reader.seek(SeekFrom::Start(0))?;
+		out.seek(SeekFrom::Start(0))?;
+
+		let mut buf = vec![0; HAPI_CHUNK_SIZE as usize];
+		let mut pos = 0;
+		loop {
+			let count = reader.read(&mut buf)?;
+			if count == 0 {
+				break;
+			}
+
+			cipher(new_key, toc_offset, pos, &mut buf[..count]);
+			out.write_all(&buf[..count])?;
+			pos += count as u64;
+		}
+
+		// The header is never enciphered, so its key can just be overwritten
+		out.seek(SeekFrom::Start(HEADER_KEY_OFFSET))?;
+		out.write_all(&new_key.map_or(0, encode_key).to_le_bytes())?;
+		out.flush()?;
+
+		Ok(())
+	}
+
+	// Copies the raw archive to `out`, enciphered exactly as it's stored
+	fn copy_archive(&self, out: &mut impl Write) -> Result<(), HapiError> {
+		let mut reader = self.reader();
+		reader.seek(SeekFrom::Start(0))?;
+
+		let mut buf = vec![0; HAPI_CHUNK_SIZE as usize];
+		let mut pos = 0;
+		loop {
+			let count = reader.read(&mut buf)?;
+			if count == 0 {
+				return Ok(());
+			}
+
+			// reading deciphered it, so put the cipher back
+			reader.apply_cipher(pos, &mut buf[..count]);
+			out.write_all(&buf[..count])?;
+			pos += count as u64;
+		}
+	}
+
+	// Collects the position of each wrong checksum field under `dir`, along with the
+	// checksum that belongs there
+	fn find_bad_checksums(
+		&self,
+		dir: &HapiDirectory,
+		fixed: &mut Vec<(u64, u32)>,
+	) -> Result<(), HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			match entry {
+				HapiEntry::File(file) => self.find_bad_file_checksums(file, fixed)?,
+				HapiEntry::Directory(dir) => self.find_bad_checksums(dir, fixed)?,
+			}
+		}
+
+		Ok(())
+	}
+
+	fn find_bad_file_checksums(
+		&self,
+		file: &HapiFile,
+		fixed: &mut Vec<(u64, u32)>,
+	) -> Result<(), HapiError> {
+		for offset in self
+			.chunk_ranges(file)?
+			.into_iter()
+			.map(|range| range.start)
+		{
+			let mut reader = self.reader();
+			reader.seek(SeekFrom::Start(offset))?;
+			let chunk = HapiCompressedChunk::read_args(&mut *reader, (false,))?;
+			if !chunk.checksum_ok() {
+				fixed.push((offset + CHUNK_CHECKSUM_OFFSET, chunk_checksum(&chunk.data)));
+			}
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,99 @@
+use super::*;
+
+use std::time::SystemTime;
+
+/// Somewhere extracted files and directories can be written to.
+///
+/// [`HapiArchive::extract_dir_to`] hands every directory and file it extracts to a
+/// sink, so implementing this trait lets an archive be extracted into something other
+/// than the local filesystem, like an in-memory tree or a remote store. [`FsSink`] is
+/// the implementation used by the plain extraction methods.
+///
+/// Paths passed to the sink are the destination paths of the entries, with names mapped
+/// according to [`ExtractOptions::sanitize_names`]. Parent directories are always
+/// created before anything inside them.
+///
+/// # Examples
+/// ```no_run
+/// use hapi::prelude::*;
+/// use std::collections::BTreeMap;
+/// use std::fs::File;
+/// use std::io;
+/// use std::path::{Path, PathBuf};
+///
+/// // Collects file names and sizes instead of writing anything
+/// #[derive(Default)]
+/// struct SizeSink(BTreeMap<PathBuf, u64>);
+///
+/// impl ExtractSink for SizeSink {
+/// 	type File = io::Sink;
+///
+/// 	fn create_dir(&mut self, _path: &Path) -> io::Result<()> {
+/// 		Ok(())
+/// 	}
+///
+/// 	fn create_file(&mut self, path: &Path) -> io::Result<io::Sink> {
+/// 		self.0.insert(path.to_path_buf(), 0);
+/// 		Ok(io::sink())
+/// 	}
+/// }
+///
+/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+/// let mut sink = SizeSink::default();
+/// archive.extract_dir_to(&archive.root_dir, &mut sink, ExtractOptions::new())?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub trait ExtractSink {
+	/// The writer returned for each new file.
+	type File: Write;
+
+	/// Creates the directory at `path`, if it doesn't exist already.
+	///
+	/// If something other than a directory is already at `path`, this should fail with
+	/// [`AlreadyExists`](io::ErrorKind::AlreadyExists), which is reported as
+	/// [`HapiError::DestConflict`].
+	fn create_dir(&mut self, path: &Path) -> io::Result<()>;
+
+	/// Creates (or truncates) the file at `path` and returns a writer for its contents.
+	fn create_file(&mut self, path: &Path) -> io::Result<Self::File>;
+
+	/// Returns whether something already exists at `path`, for
+	/// [`ExtractOptions::on_conflict`]. The default implementation always returns `false`.
+	fn exists(&mut self, path: &Path) -> bool {
+		let _ = path;
+		false
+	}
+
+	/// Sets the modification time of the file at `path`, for
+	/// [`ExtractOptions::set_mtime`]. It's called after the file's writer is dropped.
+	/// The default implementation does nothing.
+	fn set_modified(&mut self, path: &Path, time: SystemTime) -> io::Result<()> {
+		let _ = (path, time);
+		Ok(())
+	}
+}
+
+/// An [`ExtractSink`] that writes to the local filesystem, with paths used as given.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsSink;
+
+impl ExtractSink for FsSink {
+	type File = File;
+
+	fn create_dir(&mut self, path: &Path) -> io::Result<()> {
+		fs::create_dir_all(path)
+	}
+
+	fn create_file(&mut self, path: &Path) -> io::Result<File> {
+		File::create(path)
+	}
+
+	fn exists(&mut self, path: &Path) -> bool {
+		// don't follow symlinks, a dangling one is still in the way
+		path.symlink_metadata().is_ok()
+	}
+
+	fn set_modified(&mut self, path: &Path, time: SystemTime) -> io::Result<()> {
+		File::options().write(true).open(path)?.set_modified(time)
+	}
+}
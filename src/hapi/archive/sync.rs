@@ -0,0 +1,198 @@
+use super::*;
+
+/// Settings for [`HapiArchive::sync_to_dir`].
+#[derive(Debug, Default)]
+pub struct SyncOptions<'a> {
+	extract: ExtractOptions<'a>,
+	delete: bool,
+	dry_run: bool,
+}
+
+impl<'a> SyncOptions<'a> {
+	/// Returns the default options, which extract like [`HapiArchive::extract_all`] and
+	/// don't delete anything.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sets the options used to extract the archive's files.
+	pub fn extract_options(mut self, options: ExtractOptions<'a>) -> Self {
+		self.extract = options;
+		self
+	}
+
+	/// Sets whether files and directories under the destination that aren't in the
+	/// archive get removed. Defaults to `false`.
+	pub fn delete(mut self, delete: bool) -> Self {
+		self.delete = delete;
+		self
+	}
+
+	/// Sets whether to only report what would be removed, without extracting or deleting
+	/// anything. Defaults to `false`.
+	pub fn dry_run(mut self, dry_run: bool) -> Self {
+		self.dry_run = dry_run;
+		self
+	}
+}
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Brings the directory `dest` in line with the archive's contents.
+	///
+	/// All files in the archive are extracted into `dest`, like
+	/// [`extract_all_with`](Self::extract_all_with). If [`SyncOptions::delete`] is set,
+	/// anything under `dest` that doesn't correspond to an entry in the archive is then
+	/// removed, similar to `rsync --delete`. Returns the paths that were removed (or
+	/// with [`SyncOptions::dry_run`], the paths that would have been).
+	///
+	/// Removal never leaves `dest`: symbolic links are removed as links, not followed.
+	pub fn sync_to_dir(
+		&self,
+		dest: impl AsRef<Path>,
+		options: SyncOptions,
+	) -> Result<Vec<PathBuf>, HapiError> {
+		let SyncOptions {
+			extract,
+			delete,
+			dry_run,
+		} = options;
+		let mut removed = Vec::new();
+
+		// the stale pass has to map names the same way extraction did
+		let sanitize = extract.sanitize_names;
+
+		if !dry_run {
+			self.extract_all_with(dest.as_ref(), extract)?;
+		}
+		if delete && dest.as_ref().is_dir() {
+			self.remove_stale(
+				&self.root_dir,
+				dest.as_ref(),
+				sanitize,
+				dry_run,
+				&mut removed,
+			)?;
+		}
+
+		Ok(removed)
+	}
+
+	// Removes everything in `dest` that has no counterpart in `dir`, recursing into
+	// directories that do
+	fn remove_stale(
+		&self,
+		dir: &HapiDirectory,
+		dest: &Path,
+		sanitize: bool,
+		dry_run: bool,
+		removed: &mut Vec<PathBuf>,
+	) -> Result<(), HapiError> {
+		// an unloaded directory would make everything under it look stale
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for disk_entry in fs::read_dir(dest)? {
+			let disk_entry = disk_entry?;
+			let file_type = disk_entry.file_type()?;
+			let disk_name = disk_entry.file_name();
+
+			let counterpart = dir.iter().find(|entry| {
+				if sanitize {
+					*sanitize_name(entry.name()) == *disk_name
+				} else {
+					entry.name() == disk_name
+				}
+			});
+
+			match counterpart {
+				Some(HapiEntry::Directory(subdir)) if file_type.is_dir() => {
+					self.remove_stale(subdir, &disk_entry.path(), sanitize, dry_run, removed)?
+				}
+				Some(HapiEntry::File(_)) if file_type.is_file() => (),
+				_ => {
+					let path = disk_entry.path();
+					if !dry_run {
+						eprintln!("Removing {}", path.display());
+						if file_type.is_dir() {
+							fs::remove_dir_all(&path)?;
+						} else {
+							fs::remove_file(&path)?;
+						}
+					}
+					removed.push(path);
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	// Extracts the sample archive into a new directory and litters it with a stale file, a
+	// stale directory and a link to a file outside it
+	#[cfg(unix)]
+	fn littered(archive: &HapiArchive<io::Cursor<&[u8]>>) -> (TempDir, TempDir, Vec<PathBuf>) {
+		let tmp = TempDir::new();
+		archive.extract_all(tmp.path()).unwrap();
+
+		let outside = TempDir::new();
+		fs::write(outside.path().join("keep.txt"), "not ours").unwrap();
+
+		let stale = vec![
+			tmp.path().join("gamedata").join("old.tdf"),
+			tmp.path().join("units").join("gone"),
+			tmp.path().join("link"),
+		];
+		fs::write(&stale[0], "stale").unwrap();
+		fs::create_dir(&stale[1]).unwrap();
+		fs::write(stale[1].join("inside.txt"), "stale too").unwrap();
+		std::os::unix::fs::symlink(outside.path().join("keep.txt"), &stale[2]).unwrap();
+
+		(tmp, outside, stale)
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn dry_run_removes_nothing() {
+		let data = Fixture::new(sample()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let (tmp, _outside, stale) = littered(&archive);
+		let before = files_under(tmp.path());
+
+		let options = SyncOptions::new().delete(true).dry_run(true);
+		let mut removed = archive.sync_to_dir(tmp.path(), options).unwrap();
+		removed.sort();
+		let mut expected = stale.clone();
+		expected.sort();
+		assert_eq!(removed, expected);
+		assert_eq!(files_under(tmp.path()), before);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn delete_removes_stale_entries_only() {
+		let data = Fixture::new(sample()).build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let (tmp, outside, stale) = littered(&archive);
+
+		let options = SyncOptions::new().delete(true);
+		assert_eq!(archive.sync_to_dir(tmp.path(), options).unwrap().len(), 3);
+		assert!(stale.iter().all(|path| fs::symlink_metadata(path).is_err()));
+		assert_eq!(
+			fs::read(outside.path().join("keep.txt")).unwrap(),
+			b"not ours"
+		);
+
+		let fresh = TempDir::new();
+		archive.extract_all(fresh.path()).unwrap();
+		assert_eq!(files_under(tmp.path()), files_under(fresh.path()));
+	}
+}
@@ -0,0 +1,150 @@
+use super::*;
+
+// Whether the table of contents starting at the reader's position is stored as
+// compressed chunks, rather than as plain data. Leaves the position where it was.
+pub(in crate::hapi) fn toc_is_compressed<R: Read + Seek>(
+	reader: &mut R,
+) -> Result<bool, HapiError> {
+	let start = reader.stream_position()?;
+	let mut magic = [0; 4];
+	let compressed = match reader.read_exact(&mut magic) {
+		Ok(()) => &magic == b"SQSH",
+		// too short to hold a chunk, so it's certainly not one
+		Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+		Err(e) => return Err(e.into()),
+	};
+	reader.seek(SeekFrom::Start(start))?;
+
+	Ok(compressed)
+}
+
+// A table of contents decompressed into memory, which reads like it's still where it
+// was in the archive. Offsets within a compressed table of contents are relative to its
+// decompressed form, as if it had been stored plainly at `toc_offset`.
+#[derive(Debug)]
+pub(in crate::hapi) struct CompressedToc {
+	start: u64,
+	data: Vec<u8>,
+	pos: u64,
+}
+
+impl CompressedToc {
+	// Decompresses chunks from the reader's position (`toc_offset`) until there's a whole
+	// table of contents, which ends at `toc_size` once decompressed
+	pub(in crate::hapi) fn read<R: Read + Seek>(
+		reader: &mut HapiReader<R>,
+		zlib: &dyn ZlibBackend,
+	) -> Result<Self, HapiError> {
+		let start = reader.header.toc_offset as u64;
+		let size = reader
+			.header
+			.toc_size
+			.saturating_sub(reader.header.toc_offset) as usize;
+
+		let mut data = Vec::new();
+		while data.len() < size {
+			let chunk = HapiCompressedChunk::read_args(&mut *reader, (true,))?;
+			if chunk.decode(zlib, &Unpooled, &mut data)? == 0 {
+				break;
+			}
+		}
+
+		Ok(CompressedToc {
+			start,
+			data,
+			pos: start,
+		})
+	}
+}
+
+impl Read for CompressedToc {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		// Seeking before the table of contents is fine (binrw does it to get to offsets
+		// relative to the start), but there's nothing to read there
+		let offset = self.pos.checked_sub(self.start).ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!(
+					"offset {:#x} is before the compressed table of contents",
+					self.pos
+				),
+			)
+		})?;
+
+		let available = self.data.get(offset as usize..).unwrap_or_default();
+		let count = available.len().min(buf.len());
+		buf[..count].copy_from_slice(&available[..count]);
+		self.pos += count as u64;
+		Ok(count)
+	}
+}
+
+impl Seek for CompressedToc {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => {
+				(self.start + self.data.len() as u64).checked_add_signed(offset)
+			}
+			SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+		};
+
+		self.pos = new_pos.ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"invalid seek to a negative or overflowing position",
+			)
+		})?;
+		Ok(self.pos)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	// Builds the sample archive with its table of contents compressed in place
+	fn sample_with_compressed_toc() -> Vec<u8> {
+		// Room after the table of contents for it to grow by being compressed, so the
+		// files' data stays where its records say
+		let mut data = Fixture::new(sample()).gap_after_toc(256).build();
+		let toc_end = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+		let toc = chunk(
+			&data[HAPI_HEADER_SIZE as usize..toc_end],
+			HapiCompressionType::Lz77,
+		);
+		assert!(toc.len() <= toc_end + 256 - HAPI_HEADER_SIZE as usize);
+		data[HAPI_HEADER_SIZE as usize..toc_end + 256].fill(0);
+		data[HAPI_HEADER_SIZE as usize..][..toc.len()].copy_from_slice(&toc);
+		data
+	}
+
+	#[test]
+	fn compressed_toc_stops_at_its_end() {
+		let data = sample_with_compressed_toc();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		assert_eq!(archive.count_files(), 6);
+		let file = archive.get_file("units/sub/deep.txt").unwrap();
+		assert_eq!(archive.read_file(file).unwrap(), b"deep");
+	}
+
+	#[test]
+	fn parse_raw_decompresses_toc() {
+		let plain = Fixture::new(sample()).gap_after_toc(256).build();
+		let plain = parse_raw(io::Cursor::new(plain)).unwrap();
+		let compressed = parse_raw(io::Cursor::new(sample_with_compressed_toc())).unwrap();
+
+		let listing = |raw: &RawArchive| -> Vec<_> {
+			raw.entries
+				.iter()
+				.map(|entry| (entry.toc_position, entry.index.name()))
+				.collect()
+		};
+		assert_eq!(listing(&compressed), listing(&plain));
+		assert!(compressed
+			.entries
+			.iter()
+			.any(|entry| entry.index.name() == "deep.txt"));
+	}
+}
@@ -0,0 +1,396 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Quickly checks whether every file in the archive looks intact.
+	///
+	/// For each compressed file, this recomputes the checksum of every chunk and checks
+	/// that the chunks' declared sizes add up to the file's
+	/// [`extracted_size`](HapiFile::extracted_size). Nothing is decompressed, and it stops
+	/// at the first problem it finds, so it's a cheap gate for rejecting corrupt archives.
+	/// Errors are only returned if the archive can't be read at all.
+	///
+	/// Files whose contents would run past the end of the archive make it invalid (with
+	/// a warning saying which file). Extra bytes after the last file's contents, like
+	/// padding or a footer, are fine.
+	pub fn is_valid(&self) -> Result<bool, HapiError> {
+		let len = self.reader().seek(SeekFrom::End(0))?;
+		self.dir_is_valid(&self.root_dir, len)
+	}
+
+	fn dir_is_valid(&self, dir: &HapiDirectory, len: u64) -> Result<bool, HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			let valid = match entry {
+				HapiEntry::File(file) => self.file_is_valid(file, len)?,
+				HapiEntry::Directory(dir) => self.dir_is_valid(dir, len)?,
+			};
+
+			if !valid {
+				return Ok(false);
+			}
+		}
+
+		Ok(true)
+	}
+
+	fn file_is_valid(&self, file: &HapiFile, len: u64) -> Result<bool, HapiError> {
+		if file.extracted_size == 0 {
+			return Ok(true);
+		}
+
+		// Only content past the end matters; the archive itself may well go on past
+		// its content. Compressed files must at least have room for their chunk sizes,
+		// the chunks themselves are checked as they're read
+		let extent = match file.compression {
+			HapiCompressionType::None => file.extracted_size as u64,
+			_ => file.extracted_size.div_ceil(HAPI_CHUNK_SIZE) as u64 * 4,
+		};
+		if file.contents_offset as u64 + extent > len {
+			eprintln!(
+				"Warning: {} extends past the end of the archive",
+				file.path_str()
+			);
+			return Ok(false);
+		}
+
+		if file.compression == HapiCompressionType::None {
+			return Ok(true);
+		}
+
+		let mut checksums_ok = true;
+		let mut total = 0;
+		let result = self.for_each_chunk(file, |_, chunk| {
+			checksums_ok &= chunk.checksum_ok();
+			total += chunk.decompressed_size as u64;
+			Ok(())
+		});
+
+		match result {
+			Ok(()) => Ok(checksums_ok && total == file.extracted_size as u64),
+			// running off the end of the archive means it's corrupt, not unreadable
+			Err(HapiError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => Err(e.into()),
+			Err(_) => Ok(false),
+		}
+	}
+
+	// Reads `file`'s compressed chunks one at a time, passing each to `on_chunk` along
+	// with its position in the file, so only one is ever in memory. Like extraction, the
+	// chunks are read one after another from the end of the table of their sizes.
+	fn for_each_chunk(
+		&self,
+		file: &HapiFile,
+		mut on_chunk: impl FnMut(usize, HapiCompressedChunk) -> Result<(), HapiError>,
+	) -> Result<(), HapiError> {
+		let count = file.extracted_size.div_ceil(HAPI_CHUNK_SIZE) as usize;
+		let mut pos = file.contents_offset as u64 + count as u64 * 4;
+
+		for index in 0..count {
+			let chunk = {
+				let mut reader = self.reader();
+				reader.seek(SeekFrom::Start(pos))?;
+				let chunk = HapiCompressedChunk::read_args(&mut *reader, (false,))?;
+				pos = reader.stream_position()?;
+				chunk
+			};
+			on_chunk(index, chunk)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// The result of checking one file, from [`HapiArchive::verify_detailed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileVerifyResult {
+	/// The file's path within the archive.
+	pub path: PathBuf,
+	/// Whether the file's contents could be read from the archive at all. If not,
+	/// [`chunks`](Self::chunks) is empty.
+	pub readable: bool,
+	/// Whether the file decompressed to its [`extracted_size`](HapiFile::extracted_size).
+	pub size_ok: bool,
+	/// The result for each compressed chunk, in order. Empty for uncompressed files.
+	pub chunks: Vec<ChunkVerifyResult>,
+}
+
+/// The result of checking one compressed chunk, from [`HapiArchive::verify_detailed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkVerifyResult {
+	/// The chunk's position within its file, starting at 0.
+	pub index: usize,
+	/// Whether the chunk's data matches its checksum.
+	pub checksum_ok: bool,
+	/// Whether the chunk decompressed without errors, to the size its header gives.
+	pub size_ok: bool,
+}
+
+impl FileVerifyResult {
+	/// Returns whether nothing is wrong with the file.
+	pub fn is_ok(&self) -> bool {
+		self.readable
+			&& self.size_ok
+			&& self
+				.chunks
+				.iter()
+				.all(|chunk| chunk.checksum_ok && chunk.size_ok)
+	}
+}
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Checks every file in the archive, reporting exactly what's wrong with each.
+	///
+	/// Unlike [`is_valid`](Self::is_valid), this decompresses everything (into nowhere)
+	/// and doesn't stop at the first problem, so it takes about as long as extracting
+	/// the archive. Only one chunk is held in memory at a time, however big the files.
+	/// There's one result per file, in the order of [`walk`](Self::walk); see
+	/// [`FileVerifyResult::is_ok`]. Errors are only returned if the archive can't be
+	/// read at all.
+	pub fn verify_detailed(&self) -> Result<Vec<FileVerifyResult>, HapiError> {
+		let mut results = Vec::new();
+		self.verify_dir_detailed(&self.root_dir, &mut results)?;
+		Ok(results)
+	}
+
+	fn verify_dir_detailed(
+		&self,
+		dir: &HapiDirectory,
+		results: &mut Vec<FileVerifyResult>,
+	) -> Result<(), HapiError> {
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		for entry in dir {
+			match entry {
+				HapiEntry::File(file) => results.push(self.verify_file_detailed(file)?),
+				HapiEntry::Directory(dir) => self.verify_dir_detailed(dir, results)?,
+			}
+		}
+
+		Ok(())
+	}
+
+	fn verify_file_detailed(&self, file: &HapiFile) -> Result<FileVerifyResult, HapiError> {
+		let mut result = FileVerifyResult {
+			path: file.path.clone(),
+			readable: true,
+			size_ok: true,
+			chunks: Vec::new(),
+		};
+		if file.extracted_size == 0 {
+			return Ok(result);
+		}
+
+		// Decompressed data is only counted, not kept
+		let mut total = 0;
+		let outcome = if file.compression == HapiCompressionType::None {
+			self.copy_uncompressed(file, |piece| {
+				total += piece.len() as u64;
+				Ok(())
+			})
+			.map(|_| ())
+		} else {
+			self.for_each_chunk(file, |index, chunk| {
+				let size = chunk.decode(&*self.zlib, &*self.pool, &mut io::sink()).ok();
+				total += size.unwrap_or(0);
+				result.chunks.push(ChunkVerifyResult {
+					index,
+					checksum_ok: chunk.checksum_ok(),
+					size_ok: size == Some(chunk.decompressed_size as u64),
+				});
+				Ok(())
+			})
+		};
+
+		match outcome {
+			Ok(()) => result.size_ok = total == file.extracted_size as u64,
+			// running off the end of the archive means it's corrupt, not unreadable
+			Err(HapiError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => {
+				return Err(e.into())
+			}
+			Err(_) => {
+				result.readable = false;
+				result.size_ok = false;
+				result.chunks.clear();
+			}
+		}
+
+		Ok(result)
+	}
+}
+
+/// A structural problem with an archive's directory tree, from
+/// [`HapiArchive::validate_tree`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TreeAnomaly {
+	/// Two entries of the same kind have the same path.
+	DuplicatePath {
+		/// The path they share.
+		path: PathBuf,
+	},
+	/// A file and a directory have the same path, so they can't both be extracted.
+	FileDirClash {
+		/// The path they share.
+		path: PathBuf,
+	},
+	/// Two directories share the same record, so they list the same entries.
+	SharedDirectory {
+		/// The directory found first.
+		first: PathBuf,
+		/// The directory found second.
+		second: PathBuf,
+	},
+	/// The data of two files overlaps within the archive. Files whose data is exactly
+	/// the same range aren't reported, since archives share data between identical
+	/// files on purpose (see [`HapiArchive::shared_data_files`]).
+	OverlappingData {
+		/// The file whose data starts first.
+		first: PathBuf,
+		/// The file whose data starts within `first`'s.
+		second: PathBuf,
+	},
+}
+
+impl<R> HapiArchive<R>
+where
+	R: Read + Seek,
+{
+	/// Checks the archive's directory tree for entries that parse fine, but don't make
+	/// sense together.
+	///
+	/// This catches the kind of corruption that [`is_valid`](Self::is_valid) can't: two
+	/// entries with the same path, directories sharing a record, and files whose data
+	/// partly overlaps (see [`file_data_range`](Self::file_data_range)). An empty `Vec`
+	/// means nothing was found. Nothing is decompressed, but the table of chunk sizes of
+	/// each compressed file is read. Errors are only returned if the archive can't be
+	/// read at all.
+	pub fn validate_tree(&self) -> Result<Vec<TreeAnomaly>, HapiError> {
+		let mut anomalies = Vec::new();
+		let mut dirs = HashMap::new();
+		let mut ranges = Vec::new();
+		self.validate_dir(&self.root_dir, &mut anomalies, &mut dirs, &mut ranges)?;
+
+		// Files sharing the very same data are fine, so only the first of each is kept
+		ranges.sort_by_key(|(range, _): &(Range<u64>, PathBuf)| (range.start, range.end));
+		ranges.dedup_by(|(second, _), (first, _)| second == first);
+
+		// Sweep through the files in order of where their data starts, keeping track of
+		// whichever reaches the furthest so far
+		let mut furthest: Option<(u64, &Path)> = None;
+		for (range, path) in &ranges {
+			match furthest {
+				Some((end, first)) if range.start < end => {
+					anomalies.push(TreeAnomaly::OverlappingData {
+						first: first.to_path_buf(),
+						second: path.clone(),
+					});
+					if range.end > end {
+						furthest = Some((range.end, path));
+					}
+				}
+				_ => furthest = Some((range.end, path)),
+			}
+		}
+
+		Ok(anomalies)
+	}
+
+	fn validate_dir(
+		&self,
+		dir: &HapiDirectory,
+		anomalies: &mut Vec<TreeAnomaly>,
+		dirs: &mut HashMap<u32, PathBuf>,
+		ranges: &mut Vec<(Range<u64>, PathBuf)>,
+	) -> Result<(), HapiError> {
+		if let Some(first) = dirs.get(&dir.entry_offset()) {
+			anomalies.push(TreeAnomaly::SharedDirectory {
+				first: first.clone(),
+				second: dir.path.clone(),
+			});
+			// it's all been checked already, and it could be its own ancestor
+			return Ok(());
+		}
+		dirs.insert(dir.entry_offset(), dir.path.clone());
+
+		let mut loaded = None;
+		let dir = self.ensure_loaded(dir, &mut loaded)?;
+
+		let mut names = HashMap::new();
+		for entry in dir {
+			if let Some(kind) = names.insert(entry.name(), entry.kind()) {
+				let path = PathBuf::from(entry.path_str());
+				anomalies.push(if kind == entry.kind() {
+					TreeAnomaly::DuplicatePath { path }
+				} else {
+					TreeAnomaly::FileDirClash { path }
+				});
+			}
+
+			match entry {
+				HapiEntry::File(file) => match self.file_data_range(file) {
+					Ok(range) if !range.is_empty() => ranges.push((range, file.path.clone())),
+					Ok(_) => (),
+					// a size table past the end is for is_valid to complain about
+					Err(HapiError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+					Err(e) => return Err(e),
+				},
+				HapiEntry::Directory(subdir) => {
+					self.validate_dir(subdir, anomalies, dirs, ranges)?
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hapi::test_support::*;
+
+	// Points the record of the file at `path` to `contents_offset`
+	fn move_data(data: &mut [u8], path: &str, contents_offset: u32) {
+		let archive = HapiArchive::open_slice(&*data).unwrap();
+		let record = archive.get_file(path).unwrap().entry_offset() as usize;
+		data[record..record + 4].copy_from_slice(&contents_offset.to_le_bytes());
+	}
+
+	#[test]
+	fn only_partly_overlapping_data_is_an_anomaly() {
+		let data = Fixture::new(vec![
+			raw_file("a.txt", pattern(100)),
+			raw_file("b.txt", pattern(100)),
+		])
+		.build();
+		let archive = HapiArchive::open_slice(&data).unwrap();
+		let start = archive.get_file("a.txt").unwrap().contents_offset;
+		assert!(archive.validate_tree().unwrap().is_empty());
+
+		let mut shared = data.clone();
+		move_data(&mut shared, "b.txt", start);
+		let archive = HapiArchive::open_slice(&shared).unwrap();
+		assert_eq!(archive.shared_data_files().len(), 1);
+		assert!(archive.validate_tree().unwrap().is_empty());
+
+		let mut overlapping = data.clone();
+		move_data(&mut overlapping, "b.txt", start + 50);
+		let archive = HapiArchive::open_slice(&overlapping).unwrap();
+		assert!(matches!(
+			&archive.validate_tree().unwrap()[..],
+			[TreeAnomaly::OverlappingData { first, second }]
+				if first == Path::new("./a.txt") && second == Path::new("./b.txt")
+		));
+	}
+}
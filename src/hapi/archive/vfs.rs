@@ -0,0 +1,192 @@
+use super::*;
+
+/// A read-only, [`std::fs`]-shaped view of an archive.
+///
+/// This is for handing an archive to code that expects to find its files by path, like
+/// an asset loader with its own virtual filesystem layer. Paths are looked up with
+/// [`HapiDirectory::get`] from the archive root, and failures are reported as I/O
+/// errors of the same kinds `std::fs` would use ([`NotFound`](io::ErrorKind::NotFound),
+/// [`NotADirectory`](io::ErrorKind::NotADirectory) and so on).
+///
+/// Only loaded directories can be seen into; see [`OpenOptions::lazy`].
+///
+/// # Examples
+/// ```no_run
+/// use hapi::prelude::*;
+/// use hapi::ArchiveFs;
+/// use std::fs::File;
+/// use std::io::Read;
+///
+/// let archive = HapiArchive::open(File::open("Example.ufo")?)?;
+/// let fs = ArchiveFs::new(&archive);
+/// for entry in fs.read_dir("units")? {
+/// 	if entry.metadata().is_file() {
+/// 		let mut text = String::new();
+/// 		fs.open(entry.path())?.read_to_string(&mut text)?;
+/// 	}
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveFs<'a, R: Read + Seek> {
+	archive: &'a HapiArchive<R>,
+}
+
+/// Metadata about an entry in an [`ArchiveFs`], like [`std::fs::Metadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveMetadata {
+	is_dir: bool,
+	len: u64,
+}
+
+/// An entry in a directory of an [`ArchiveFs`], like [`std::fs::DirEntry`].
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveDirEntry<'a> {
+	entry: &'a HapiEntry,
+}
+
+/// An iterator over the entries of a directory in an [`ArchiveFs`], returned by
+/// [`ArchiveFs::read_dir`].
+#[derive(Debug, Clone)]
+pub struct ArchiveReadDir<'a> {
+	inner: std::slice::Iter<'a, HapiEntry>,
+}
+
+impl<'a, R> ArchiveFs<'a, R>
+where
+	R: Read + Seek,
+{
+	/// Wraps `archive`.
+	pub fn new(archive: &'a HapiArchive<R>) -> Self {
+		ArchiveFs { archive }
+	}
+
+	/// Opens the file at `path` for reading, like [`File::open`].
+	pub fn open(&self, path: impl AsRef<Path>) -> io::Result<HapiFileReader<'a, R>> {
+		let file = self.file(path.as_ref())?;
+		Ok(self.archive.open_file(file)?)
+	}
+
+	/// Reads the entire contents of the file at `path`, like [`fs::read`].
+	pub fn read(&self, path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+		let file = self.file(path.as_ref())?;
+		Ok(self.archive.read_file(file)?)
+	}
+
+	/// Returns the entries of the directory at `path`, like [`fs::read_dir`].
+	pub fn read_dir(&self, path: impl AsRef<Path>) -> io::Result<ArchiveReadDir<'a>> {
+		let path = path.as_ref();
+		match self.archive.get_dir(path) {
+			Some(dir) => Ok(ArchiveReadDir { inner: dir.iter() }),
+			None if self.archive.get_file(path).is_some() => Err(io::Error::new(
+				io::ErrorKind::NotADirectory,
+				format!("{} is not a directory", path.display()),
+			)),
+			None => Err(not_found(path)),
+		}
+	}
+
+	/// Returns metadata about the file or directory at `path`, like [`fs::metadata`].
+	pub fn metadata(&self, path: impl AsRef<Path>) -> io::Result<ArchiveMetadata> {
+		let path = path.as_ref();
+		match self.archive.get_dir(path) {
+			Some(dir) => Ok(ArchiveMetadata {
+				is_dir: true,
+				len: dir.total_size(),
+			}),
+			None => Ok(ArchiveMetadata::of(
+				self.archive
+					.root_dir
+					.get(path)
+					.ok_or_else(|| not_found(path))?,
+			)),
+		}
+	}
+
+	/// Returns whether there's a file or directory at `path`.
+	pub fn exists(&self, path: impl AsRef<Path>) -> bool {
+		self.metadata(path).is_ok()
+	}
+
+	fn file(&self, path: &Path) -> io::Result<&'a HapiFile> {
+		match self.archive.root_dir.get(path) {
+			Some(HapiEntry::File(file)) => Ok(file),
+			Some(HapiEntry::Directory(_)) => Err(io::Error::new(
+				io::ErrorKind::IsADirectory,
+				format!("{} is a directory", path.display()),
+			)),
+			None => Err(not_found(path)),
+		}
+	}
+}
+
+fn not_found(path: &Path) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::NotFound,
+		format!("{} not found in archive", path.display()),
+	)
+}
+
+impl ArchiveMetadata {
+	fn of(entry: &HapiEntry) -> Self {
+		ArchiveMetadata {
+			is_dir: entry.as_dir().is_some(),
+			len: entry.total_size(),
+		}
+	}
+
+	/// Returns whether this is a directory.
+	pub fn is_dir(&self) -> bool {
+		self.is_dir
+	}
+
+	/// Returns whether this is a file.
+	pub fn is_file(&self) -> bool {
+		!self.is_dir
+	}
+
+	/// Returns a file's decompressed size, or for a directory, the total decompressed
+	/// size of everything under it (see [`HapiDirectory::total_size`]).
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+
+	/// Returns whether [`len`](Self::len) is 0.
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+}
+
+impl<'a> ArchiveDirEntry<'a> {
+	/// Returns the entry's full path within the archive, which can be passed back to
+	/// [`ArchiveFs`].
+	pub fn path(&self) -> &'a Path {
+		match self.entry {
+			HapiEntry::File(file) => file.path(),
+			HapiEntry::Directory(dir) => dir.path(),
+		}
+	}
+
+	/// Returns just the entry's name.
+	pub fn file_name(&self) -> &'a str {
+		self.entry.name()
+	}
+
+	/// Returns metadata about the entry.
+	pub fn metadata(&self) -> ArchiveMetadata {
+		ArchiveMetadata::of(self.entry)
+	}
+
+	/// Returns the underlying archive entry.
+	pub fn entry(&self) -> &'a HapiEntry {
+		self.entry
+	}
+}
+
+impl<'a> Iterator for ArchiveReadDir<'a> {
+	type Item = ArchiveDirEntry<'a>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.inner.next().map(|entry| ArchiveDirEntry { entry })
+	}
+}
@@ -0,0 +1,40 @@
+use super::*;
+
+use libflate::zlib;
+
+/// A zlib decompressor, used for chunks compressed with [`HapiCompressionType::Zlib`].
+///
+/// The default is [`Libflate`]. Implement this to decompress with something faster
+/// (like `flate2` with a native backend), and pass it to [`OpenOptions::zlib_backend`].
+///
+/// # Examples
+/// ```ignore
+/// use hapi::prelude::*;
+/// use std::io::{self, Read, Write};
+///
+/// #[derive(Debug)]
+/// struct Flate2;
+///
+/// impl ZlibBackend for Flate2 {
+/// 	fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<u64> {
+/// 		io::copy(&mut flate2::read::ZlibDecoder::new(input), output)
+/// 	}
+/// }
+///
+/// let options = OpenOptions::new().zlib_backend(Flate2);
+/// ```
+pub trait ZlibBackend: Debug + Send + Sync {
+	/// Decompresses the zlib stream `input` into `output`, returning the number of bytes
+	/// written.
+	fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<u64>;
+}
+
+/// The default [`ZlibBackend`], using the pure-Rust `libflate` crate.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Libflate;
+
+impl ZlibBackend for Libflate {
+	fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<u64> {
+		io::copy(&mut zlib::Decoder::new(input)?, output)
+	}
+}
@@ -0,0 +1,309 @@
+use super::{ChunkTooLarge, InvalidUtf8Name, TooDeep, HAPI_HEADER_SIZE};
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// The error type for reading and extracting HAPI archives.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum HapiError {
+	/// An I/O error from the underlying stream or the filesystem.
+	Io(io::Error),
+	/// Part of the archive couldn't be parsed.
+	Parse(binrw::Error),
+	/// The stream is too short to even hold a HAPI header.
+	TooSmall {
+		/// The length of the stream, in bytes.
+		len: u64,
+	},
+	/// The stream doesn't start with the HAPI magic number, so it's not a HAPI archive
+	/// at all.
+	NotAnArchive,
+	/// The stream starts like a HAPI archive, but its header couldn't be parsed.
+	CorruptHeader(binrw::Error),
+	/// The header's marker isn't one that's known, with
+	/// [`OpenOptions::on_unknown_marker`] set to [`UnknownMarkerPolicy::Error`].
+	///
+	/// [`OpenOptions::on_unknown_marker`]: super::OpenOptions::on_unknown_marker
+	/// [`UnknownMarkerPolicy::Error`]: super::UnknownMarkerPolicy::Error
+	UnknownMarker([u8; 4]),
+	/// A compressed chunk claims to hold more data than is left in the archive.
+	ChunkTooLarge {
+		/// The size the chunk gives for its data, in bytes.
+		declared: u32,
+		/// How many bytes are actually left after the chunk's header.
+		available: u64,
+	},
+	/// An entry's name isn't valid UTF-8, with [`NameEncoding::Utf8Strict`] in use.
+	///
+	/// [`NameEncoding::Utf8Strict`]: super::NameEncoding::Utf8Strict
+	InvalidUtf8Name {
+		/// The name exactly as stored.
+		raw_bytes: Vec<u8>,
+		/// Where the entry's record is within the archive (see
+		/// [`HapiEntryIndex::entry_offset`](super::HapiEntryIndex::entry_offset)).
+		entry_offset: u32,
+		/// The path of the directory the entry is in, like [`HapiDirectory::path`].
+		///
+		/// [`HapiDirectory::path`]: super::HapiDirectory::path
+		parent: PathBuf,
+	},
+	/// The archive's directories are nested deeper than [`OpenOptions::max_depth`]
+	/// allows.
+	///
+	/// [`OpenOptions::max_depth`]: super::OpenOptions::max_depth
+	TooDeep {
+		/// The directory whose entries are past the limit.
+		path: PathBuf,
+		/// The limit, in levels below the archive root.
+		limit: usize,
+	},
+	/// The destination given for extraction doesn't exist or isn't a directory.
+	DestNotADirectory {
+		/// The offending destination.
+		path: PathBuf,
+	},
+	/// Something other than a directory is in the way of a directory being extracted.
+	DestConflict {
+		/// Where the directory was supposed to go.
+		path: PathBuf,
+	},
+	/// A file decompressed to more than [`ExtractOptions::max_output_size`] allows.
+	///
+	/// [`ExtractOptions::max_output_size`]: super::ExtractOptions::max_output_size
+	OutputTooLarge {
+		/// The file's path within the archive.
+		path: PathBuf,
+		/// The limit that was exceeded, in bytes.
+		limit: u64,
+	},
+	/// A file didn't decompress to its recorded size, with
+	/// [`ExtractOptions::check_file_size`] set.
+	///
+	/// [`ExtractOptions::check_file_size`]: super::ExtractOptions::check_file_size
+	FileSizeMismatch {
+		/// The file's path within the archive.
+		path: PathBuf,
+		/// The size recorded in the archive, in bytes.
+		expected: u64,
+		/// The number of bytes it actually decompressed to.
+		actual: u64,
+	},
+	/// A file's contents don't have the CRC-32 they were expected to, from one of the
+	/// `*_checked` methods of [`HapiArchive`].
+	///
+	/// [`HapiArchive`]: super::HapiArchive
+	HashMismatch {
+		/// The file's path within the archive.
+		path: PathBuf,
+		/// The CRC-32 that was expected.
+		expected: u32,
+		/// The CRC-32 of the file's actual contents.
+		actual: u32,
+	},
+}
+
+impl fmt::Display for HapiError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			HapiError::Io(e) => e.fmt(f),
+			HapiError::Parse(e) => e.fmt(f),
+			HapiError::TooSmall { len } => write!(
+				f,
+				"File is too small to be a HAPI archive ({} bytes, header is {})",
+				len, HAPI_HEADER_SIZE
+			),
+			HapiError::NotAnArchive => f.write_str("Not a HAPI archive"),
+			HapiError::CorruptHeader(e) => write!(f, "Corrupt HAPI header: {}", e),
+			HapiError::UnknownMarker(marker) => write!(f, "Unknown header marker {:x?}", marker),
+			HapiError::ChunkTooLarge {
+				declared,
+				available,
+			} => write!(
+				f,
+				"Chunk claims {} bytes of data, but only {} are left in the archive",
+				declared, available
+			),
+			HapiError::InvalidUtf8Name {
+				raw_bytes,
+				entry_offset,
+				parent,
+			} => write!(
+				f,
+				"Name of entry at {:#x} in {} is not valid UTF-8 (\"{}\")",
+				entry_offset,
+				parent.display(),
+				raw_bytes.escape_ascii()
+			),
+			HapiError::TooDeep { path, limit } => write!(
+				f,
+				"Entries of {} are nested more than {} levels deep",
+				path.display(),
+				limit
+			),
+			HapiError::DestNotADirectory { path } => write!(
+				f,
+				"Extraction destination {} is not a directory",
+				path.display()
+			),
+			HapiError::DestConflict { path } => write!(
+				f,
+				"Can't create directory {}, something else is already there",
+				path.display()
+			),
+			HapiError::OutputTooLarge { path, limit } => write!(
+				f,
+				"{} decompresses to more than the limit of {} bytes",
+				path.display(),
+				limit
+			),
+			HapiError::FileSizeMismatch {
+				path,
+				expected,
+				actual,
+			} => write!(
+				f,
+				"{} decompressed to {} bytes, but should be {}",
+				path.display(),
+				actual,
+				expected
+			),
+			HapiError::HashMismatch {
+				path,
+				expected,
+				actual,
+			} => write!(
+				f,
+				"{} has CRC-32 {:08x}, but should have {:08x}",
+				path.display(),
+				actual,
+				expected
+			),
+		}
+	}
+}
+
+impl Error for HapiError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			HapiError::Io(e) => Some(e),
+			HapiError::Parse(e) => Some(e),
+			HapiError::CorruptHeader(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for HapiError {
+	fn from(e: io::Error) -> Self {
+		HapiError::Io(e)
+	}
+}
+
+impl From<binrw::Error> for HapiError {
+	fn from(e: binrw::Error) -> Self {
+		match e {
+			binrw::Error::Io(e) => HapiError::Io(e),
+			e => {
+				if let Some(err) = find_custom::<ChunkTooLarge>(&e) {
+					HapiError::ChunkTooLarge {
+						declared: err.declared,
+						available: err.available,
+					}
+				} else if let Some(err) = find_custom::<InvalidUtf8Name>(&e) {
+					HapiError::InvalidUtf8Name {
+						raw_bytes: err.raw_bytes.clone(),
+						entry_offset: err.entry_offset,
+						parent: err.parent.clone(),
+					}
+				} else if let Some(err) = find_custom::<TooDeep>(&e) {
+					HapiError::TooDeep {
+						path: err.path.clone(),
+						limit: err.limit,
+					}
+				} else {
+					HapiError::Parse(e)
+				}
+			}
+		}
+	}
+}
+
+// Digs one of our own errors out of whatever binrw wrapped it in on the way up
+fn find_custom<T: binrw::error::CustomError + 'static>(e: &binrw::Error) -> Option<&T> {
+	match e {
+		binrw::Error::Custom { err, .. } => err.downcast_ref(),
+		binrw::Error::Backtrace(backtrace) => find_custom(&backtrace.error),
+		binrw::Error::EnumErrors { variant_errors, .. } => {
+			variant_errors.iter().find_map(|(_, e)| find_custom(e))
+		}
+		_ => None,
+	}
+}
+
+/// Something odd about an archive that wasn't bad enough to stop it from opening, from
+/// [`HapiArchive::warnings`](super::HapiArchive::warnings).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HapiWarning {
+	/// The header's marker isn't one that's known, with
+	/// [`OpenOptions::on_unknown_marker`] set to [`UnknownMarkerPolicy::Warn`].
+	///
+	/// [`OpenOptions::on_unknown_marker`]: super::OpenOptions::on_unknown_marker
+	/// [`UnknownMarkerPolicy::Warn`]: super::UnknownMarkerPolicy::Warn
+	UnknownMarker([u8; 4]),
+	/// The header says the table of contents ends before there's room for the root
+	/// directory, with [`OpenOptions::on_small_toc`] set to [`SmallTocPolicy::Warn`].
+	///
+	/// [`OpenOptions::on_small_toc`]: super::OpenOptions::on_small_toc
+	/// [`SmallTocPolicy::Warn`]: super::SmallTocPolicy::Warn
+	SmallToc {
+		/// Where the header says the table of contents ends (see
+		/// [`HapiHeader::toc_size`](super::HapiHeader::toc_size)).
+		toc_size: u32,
+	},
+	/// An entry has flags set besides the one marking directories. Entries in
+	/// directories that are only parsed later (see [`OpenOptions::lazy`]) aren't
+	/// checked.
+	///
+	/// [`OpenOptions::lazy`]: super::OpenOptions::lazy
+	UnknownFlags {
+		/// The entry's path within the archive.
+		path: PathBuf,
+		/// The entry's flag byte, as stored.
+		flags: u8,
+	},
+}
+
+impl fmt::Display for HapiWarning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			HapiWarning::UnknownMarker(marker) => {
+				write!(f, "Unknown header marker {:x?}", marker)
+			}
+			HapiWarning::SmallToc { toc_size } => write!(
+				f,
+				"Table of contents ending at {:#x} is too small to hold the root directory. \
+				 Archive may be corrupt.",
+				toc_size
+			),
+			HapiWarning::UnknownFlags { path, flags } => write!(
+				f,
+				"{} has unknown flags set ({:#04x})",
+				path.display(),
+				flags
+			),
+		}
+	}
+}
+
+impl From<HapiError> for io::Error {
+	fn from(e: HapiError) -> Self {
+		match e {
+			HapiError::Io(e) => e,
+			e => io::Error::new(io::ErrorKind::InvalidData, e),
+		}
+	}
+}
@@ -0,0 +1,69 @@
+use std::borrow::Cow;
+
+/// How entry names are decoded from the bytes stored in an archive.
+///
+/// Names are plain byte strings in the format, and TA was localized, so not every
+/// archive uses UTF-8 (or ASCII, as most do). Set this with
+/// [`OpenOptions::name_encoding`](super::OpenOptions::name_encoding).
+#[derive(Debug, Default, Clone, Copy)]
+#[non_exhaustive]
+pub enum NameEncoding {
+	/// UTF-8, with invalid sequences replaced by U+FFFD. ASCII names, which is nearly
+	/// all of them, come out the same under any encoding.
+	#[default]
+	Utf8Lossy,
+	/// UTF-8, with names that aren't valid UTF-8 rejected: opening an archive with one
+	/// (or loading the directory it's in) fails with [`HapiError::InvalidUtf8Name`].
+	///
+	/// [`HapiError::InvalidUtf8Name`]: super::HapiError::InvalidUtf8Name
+	Utf8Strict,
+	/// Windows-1252, the Western European codepage, as used by English and most
+	/// European releases of TA. Every byte decodes to something.
+	Windows1252,
+	/// A custom decoder, for other codepages (like Shift-JIS, via a crate such as
+	/// `encoding_rs`).
+	Custom(fn(&[u8]) -> String),
+}
+
+// Code points for 0x80..=0x9f in Windows-1252, where it differs from Latin-1. The five
+// bytes it leaves undefined map to the matching C1 control instead, like browsers do
+const WINDOWS_1252_HIGH: [char; 32] = [
+	'\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+	'\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+	'\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+	'\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+impl NameEncoding {
+	/// Decodes a name as stored into a `String`.
+	///
+	/// This never fails: with [`Utf8Strict`](Self::Utf8Strict), invalid names are
+	/// decoded like [`Utf8Lossy`](Self::Utf8Lossy) does. Use
+	/// [`try_decode`](Self::try_decode) to tell when that happens.
+	pub fn decode<'a>(&self, name: &'a [u8]) -> Cow<'a, str> {
+		match self {
+			NameEncoding::Utf8Lossy | NameEncoding::Utf8Strict => String::from_utf8_lossy(name),
+			NameEncoding::Windows1252 => match std::str::from_utf8(name) {
+				Ok(ascii) if ascii.is_ascii() => Cow::Borrowed(ascii),
+				_ => Cow::Owned(
+					name.iter()
+						.map(|&b| match b {
+							0x80..=0x9f => WINDOWS_1252_HIGH[b as usize - 0x80],
+							_ => b as char,
+						})
+						.collect(),
+				),
+			},
+			NameEncoding::Custom(decode) => Cow::Owned(decode(name)),
+		}
+	}
+
+	/// Like [`decode`](Self::decode), but returns `None` if the encoding is
+	/// [`Utf8Strict`](Self::Utf8Strict) and `name` isn't valid UTF-8.
+	pub fn try_decode<'a>(&self, name: &'a [u8]) -> Option<Cow<'a, str>> {
+		match self {
+			NameEncoding::Utf8Strict => std::str::from_utf8(name).ok().map(Cow::Borrowed),
+			_ => Some(self.decode(name)),
+		}
+	}
+}
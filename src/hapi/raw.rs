@@ -0,0 +1,111 @@
+use super::*;
+
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+
+/// The table of contents of an archive, as stored, from [`parse_raw`].
+#[derive(Debug, Clone)]
+pub struct RawArchive {
+	/// The archive's header.
+	pub header: HapiHeader,
+	/// Anything odd about the header that didn't stop it from being parsed.
+	pub warnings: Vec<HapiWarning>,
+	/// Every index entry in the table of contents, directory by directory, starting with
+	/// the root's.
+	pub entries: Vec<RawEntry>,
+}
+
+/// One index entry from an archive's table of contents, with where it was found.
+#[derive(Debug, Clone)]
+pub struct RawEntry {
+	/// Where the index entry itself is within the archive.
+	pub toc_position: u32,
+	/// Where the record of the directory listing this entry is within the archive.
+	pub parent_offset: u32,
+	/// The index entry.
+	pub index: HapiEntryIndex,
+}
+
+impl HapiEntryIndex {
+	/// Returns the entry's name, with invalid UTF-8 replaced by U+FFFD.
+	pub fn name(&self) -> String {
+		String::from_utf8_lossy(&self.name.0).into_owned()
+	}
+
+	/// Returns the entry's name exactly as stored.
+	pub fn name_bytes(&self) -> &[u8] {
+		&self.name.0
+	}
+}
+
+/// Reads the header and table of contents of a HAPI archive, without building a
+/// directory tree out of them.
+///
+/// This is a low-level view of the format for inspecting archives; to actually read
+/// one, use [`HapiArchive::open`]. Directories are visited breadth-first, and each one
+/// is only listed once, so a table of contents that loops back on itself still
+/// terminates. Nothing past the index entries (file records, names aside) is read.
+///
+/// A compressed table of contents is decompressed (with [`Libflate`]) before it's
+/// read, and positions within it are given as if it had been stored plainly at
+/// [`toc_offset`](HapiHeader::toc_offset), which is what its own offsets assume.
+pub fn parse_raw<R: Read + Seek>(stream: R) -> Result<RawArchive, HapiError> {
+	let mut reader = HapiReader::new(
+		stream,
+		UnknownMarkerPolicy::default(),
+		SmallTocPolicy::default(),
+	)?;
+	let header = reader.header.clone();
+	let warnings = mem::take(&mut reader.warnings);
+
+	reader.seek(SeekFrom::Start(header.toc_offset as u64))?;
+	let entries = if toc_is_compressed(&mut reader)? {
+		let mut toc = CompressedToc::read(&mut reader, &Libflate)?;
+		read_entries(&mut toc, header.toc_offset)?
+	} else {
+		read_entries(&mut reader, header.toc_offset)?
+	};
+
+	Ok(RawArchive {
+		header,
+		warnings,
+		entries,
+	})
+}
+
+// Lists every index entry reachable from the directory at `root_offset`
+fn read_entries<R: Read + Seek>(
+	reader: &mut R,
+	root_offset: u32,
+) -> Result<Vec<RawEntry>, HapiError> {
+	let mut entries = Vec::new();
+	let mut visited = HashSet::new();
+	let mut dirs = VecDeque::from([root_offset]);
+
+	while let Some(dir_offset) = dirs.pop_front() {
+		if !visited.insert(dir_offset) {
+			continue;
+		}
+
+		reader.seek(SeekFrom::Start(dir_offset as u64))?;
+		let count: u32 = reader.read_le()?;
+		let entries_offset: u32 = reader.read_le()?;
+
+		reader.seek(SeekFrom::Start(entries_offset as u64))?;
+		for _ in 0..count {
+			let toc_position = offset_u32(reader.stream_position()?)?;
+			let index: HapiEntryIndex = reader.read_le()?;
+			if index.is_dir {
+				dirs.push_back(index.entry_offset);
+			}
+
+			entries.push(RawEntry {
+				toc_position,
+				parent_offset: dir_offset,
+				index,
+			});
+		}
+	}
+
+	Ok(entries)
+}
@@ -1,46 +1,173 @@
 use super::*;
 
-use std::error::Error;
 use std::io::{self, prelude::*, ErrorKind, SeekFrom};
 
 use binrw::BinRead;
 
+// Size of a directory's record (its entry count and where its entries start), which is
+// the least a table of contents can hold
+const DIR_RECORD_SIZE: u32 = 8;
+
 #[derive(Debug)]
 pub(super) struct HapiReader<R: Read + Seek> {
 	inner: R,
 	pub(super) header: HapiHeader,
+	// Anything odd about the header that the policies said to carry on past
+	pub(super) warnings: Vec<HapiWarning>,
 }
 
 impl<R> HapiReader<R>
 where
 	R: Read + Seek,
 {
-	pub fn new(mut inner: R) -> Result<HapiReader<R>, Box<dyn Error>> {
-		// Parse header
-		let header = HapiHeader::read(&mut inner).map_err(|e| -> Box<dyn Error> {
-			if let binrw::error::Error::BadMagic { .. } = e {
-				io::Error::new(ErrorKind::InvalidData, "Not a HAPI archive").into()
-			} else {
-				e.into()
-			}
-		})?;
+	pub fn new(
+		mut inner: R,
+		on_unknown_marker: UnknownMarkerPolicy,
+		on_small_toc: SmallTocPolicy,
+	) -> Result<HapiReader<R>, HapiError> {
+		let start = inner.stream_position()?;
+		let (header, len) = read_header(&mut inner)?;
+		Self::checked(inner, header, start, len, on_unknown_marker, on_small_toc)
+	}
+
+	// Trusts `header` to be what's at the stream's position, so it doesn't have to be
+	// read again; it's only checked against the stream's length
+	pub fn from_parts(
+		mut inner: R,
+		header: HapiHeader,
+		on_unknown_marker: UnknownMarkerPolicy,
+		on_small_toc: SmallTocPolicy,
+	) -> Result<HapiReader<R>, HapiError> {
+		let start = inner.stream_position()?;
+		let len = inner.seek(SeekFrom::End(0))? - start;
+		inner.seek(SeekFrom::Start(start))?;
+		if len < HAPI_HEADER_SIZE {
+			return Err(HapiError::TooSmall { len });
+		}
+
+		Self::checked(inner, header, start, len, on_unknown_marker, on_small_toc)
+	}
+
+	// Checks `header`, which starts at `start` in a stream `len` bytes long from there
+	fn checked(
+		inner: R,
+		header: HapiHeader,
+		start: u64,
+		len: u64,
+		on_unknown_marker: UnknownMarkerPolicy,
+		on_small_toc: SmallTocPolicy,
+	) -> Result<HapiReader<R>, HapiError> {
+		let mut warnings = Vec::new();
+
+		// The root directory's record has to be in there somewhere
+		if header.toc_offset as u64 >= len {
+			return Err(HapiError::CorruptHeader(binrw::Error::AssertFail {
+				pos: start + 16,
+				message: format!(
+					"table of contents offset {:#x} is past the end of the archive",
+					header.toc_offset
+				),
+			}));
+		}
 
 		if header.marker == HAPI_SAVE_MARKER {
 			return Err(
 				io::Error::new(ErrorKind::InvalidData, "Save data is not supported yet").into(),
 			);
 		} else if header.marker != HAPI_ARCHIVE_MARKER {
-			// XXX how 2 warn from library
-			eprintln!(
-				"Warning: Unknown header marker {:x?}. Proceeding without caution.",
-				header.marker
-			);
+			match on_unknown_marker {
+				UnknownMarkerPolicy::Proceed => (),
+				UnknownMarkerPolicy::Warn => {
+					warnings.push(HapiWarning::UnknownMarker(header.marker))
+				}
+				UnknownMarkerPolicy::Error => {
+					return Err(HapiError::UnknownMarker(header.marker));
+				}
+			}
+		}
+
+		// Without room for the root directory, it's corrupt or not an archive at all.
+		// `toc_size` is where the table of contents ends, not how long it is.
+		if header.toc_size < header.toc_offset.saturating_add(DIR_RECORD_SIZE) {
+			match on_small_toc {
+				SmallTocPolicy::Proceed => (),
+				SmallTocPolicy::Warn => warnings.push(HapiWarning::SmallToc {
+					toc_size: header.toc_size,
+				}),
+				SmallTocPolicy::Error => {
+					return Err(HapiError::CorruptHeader(binrw::Error::AssertFail {
+						pos: start + 8,
+						message: format!(
+							"table of contents ending at {:#x} is too small to hold the root \
+							 directory",
+							header.toc_size
+						),
+					}));
+				}
+			}
 		}
 
-		Ok(HapiReader { inner, header })
+		Ok(HapiReader {
+			inner,
+			header,
+			warnings,
+		})
+	}
+
+	pub(super) fn into_inner(self) -> R {
+		self.inner
+	}
+
+	// Deciphers `buf`, which was read from `pos` in the stream, if there's a key. The
+	// cipher is its own inverse, so this also enciphers plain data for writing at `pos`
+	pub(super) fn apply_cipher(&self, pos: u64, buf: &mut [u8]) {
+		cipher(self.header.key, self.header.toc_offset, pos, buf);
+	}
+}
+
+// Applies the cipher with `key` to `buf`, which goes at `pos` in an archive whose table
+// of contents starts at `toc_offset`. Does nothing if there's no key
+pub(super) fn cipher(key: Option<u32>, toc_offset: u32, pos: u64, buf: &mut [u8]) {
+	let Some(key) = key else {
+		return;
+	};
+
+	// Decipher everything except header, which comes before the table of contents
+	let skip = (toc_offset as u64)
+		.saturating_sub(pos)
+		.min(buf.len() as u64) as usize;
+	let start = pos + skip as u64;
+
+	// Only the low byte of the offset and key matter, so the offset can just wrap
+	// around; keeping the loop simple lets it be vectorized
+	let key = key as u8;
+	for (count, byte) in buf[skip..].iter_mut().enumerate() {
+		// This is where the magic happens
+		let char_key = (start as u8).wrapping_add(count as u8) ^ key;
+		*byte = char_key ^ !*byte;
 	}
 }
 
+// Reads the header at the stream's position, returning it along with how long the
+// stream is from there
+pub(super) fn read_header<R: Read + Seek>(inner: &mut R) -> Result<(HapiHeader, u64), HapiError> {
+	// Make sure there's room for a header before trying to parse one
+	let start = inner.stream_position()?;
+	let len = inner.seek(SeekFrom::End(0))? - start;
+	inner.seek(SeekFrom::Start(start))?;
+	if len < HAPI_HEADER_SIZE {
+		return Err(HapiError::TooSmall { len });
+	}
+
+	let header = HapiHeader::read(inner).map_err(|e| match e {
+		binrw::Error::BadMagic { .. } => HapiError::NotAnArchive,
+		binrw::Error::Io(e) => HapiError::Io(e),
+		e => HapiError::CorruptHeader(e),
+	})?;
+
+	Ok((header, len))
+}
+
 // Trait impls
 
 impl<R> Read for HapiReader<R>
@@ -53,19 +180,7 @@ where
 		// Read bytes, store count
 		let bytes_count = self.inner.read(buf)?;
 
-		// Decipher if key is present
-		if let Some(key) = self.header.key {
-			for (count, byte) in buf.iter_mut().enumerate().take(bytes_count) {
-				let offset = pos as u32 + count as u32;
-
-				// Decipher everything except header
-				if offset >= self.header.toc_offset {
-					// This is where the magic happens
-					let char_key = (offset ^ key) as u8;
-					*byte = char_key ^ !*byte;
-				}
-			}
-		}
+		self.apply_cipher(pos, &mut buf[..bytes_count]);
 
 		Ok(bytes_count)
 	}
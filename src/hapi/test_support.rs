@@ -0,0 +1,422 @@
+// Builds archives in memory for tests, including broken ones that still parse far
+// enough to show how the library copes with them
+
+use super::*;
+
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libflate::zlib;
+
+// An entry to put in a test archive
+pub(crate) enum Node {
+	File {
+		name: Vec<u8>,
+		contents: Vec<u8>,
+		// how each chunk is compressed, in turn; just `None` for uncompressed files
+		chunks: Vec<HapiCompressionType>,
+		bad_checksum: bool,
+	},
+	Dir {
+		name: Vec<u8>,
+		entries: Vec<Node>,
+	},
+	// A directory entry pointing at a record somewhere else, rather than one of its own
+	DirAt {
+		name: Vec<u8>,
+		offset: u32,
+	},
+}
+
+// A file whose chunks are all compressed with zlib
+pub(crate) fn file(name: impl Into<Vec<u8>>, contents: impl Into<Vec<u8>>) -> Node {
+	mixed_file(name, contents, &[HapiCompressionType::Zlib])
+}
+
+// A file stored without compression
+pub(crate) fn raw_file(name: impl Into<Vec<u8>>, contents: impl Into<Vec<u8>>) -> Node {
+	mixed_file(name, contents, &[HapiCompressionType::None])
+}
+
+// A file whose chunks take turns at being compressed each way in `chunks`
+pub(crate) fn mixed_file(
+	name: impl Into<Vec<u8>>,
+	contents: impl Into<Vec<u8>>,
+	chunks: &[HapiCompressionType],
+) -> Node {
+	Node::File {
+		name: name.into(),
+		contents: contents.into(),
+		chunks: chunks.to_vec(),
+		bad_checksum: false,
+	}
+}
+
+pub(crate) fn dir(name: impl Into<Vec<u8>>, entries: Vec<Node>) -> Node {
+	Node::Dir {
+		name: name.into(),
+		entries,
+	}
+}
+
+// A directory whose record is at `offset`, which could well be one of its ancestors'
+pub(crate) fn dir_at(name: impl Into<Vec<u8>>, offset: u32) -> Node {
+	Node::DirAt {
+		name: name.into(),
+		offset,
+	}
+}
+
+// `levels` directories, each inside the last, with a file in the innermost one. The
+// file is `levels + 1` levels deep
+pub(crate) fn nested(levels: usize) -> Vec<Node> {
+	let mut entries = vec![file("bottom.txt", "at the bottom")];
+	for level in (0..levels).rev() {
+		entries = vec![dir(format!("d{}", level), entries)];
+	}
+	entries
+}
+
+// How to lay out a test archive: the header, then the table of contents, then each
+// file's data in the order the files were listed
+#[derive(Default)]
+pub(crate) struct Fixture {
+	entries: Vec<Node>,
+	key: Option<u32>,
+	stored_key: Option<u32>,
+	gap: usize,
+	trailing: Vec<u8>,
+}
+
+impl Fixture {
+	pub(crate) fn new(entries: Vec<Node>) -> Self {
+		Fixture {
+			entries,
+			..Default::default()
+		}
+	}
+
+	// Enciphers everything after the header with `key`, and stores it in the header
+	pub(crate) fn key(mut self, key: u32) -> Self {
+		self.key = Some(key);
+		self
+	}
+
+	// Stores `stored` as the header's key, whatever the archive is enciphered with
+	pub(crate) fn stored_key(mut self, stored: u32) -> Self {
+		self.stored_key = Some(stored);
+		self
+	}
+
+	// Leaves `len` bytes that nothing refers to between the table of contents and the
+	// first file's data
+	pub(crate) fn gap_after_toc(mut self, len: usize) -> Self {
+		self.gap = len;
+		self
+	}
+
+	// Appends `bytes` after everything else, without enciphering them
+	pub(crate) fn trailing(mut self, bytes: &[u8]) -> Self {
+		self.trailing = bytes.to_vec();
+		self
+	}
+
+	pub(crate) fn build(&self) -> Vec<u8> {
+		let mut out = vec![0; HAPI_HEADER_SIZE as usize];
+		let mut files = Vec::new();
+		write_dir(&self.entries, &mut out, &mut files);
+
+		let toc_end = out.len() as u32;
+		out.resize(out.len() + self.gap, 0);
+		for (record, node) in files {
+			let Node::File {
+				contents,
+				chunks,
+				bad_checksum,
+				..
+			} = node
+			else {
+				unreachable!("only files have data");
+			};
+
+			let offset = out.len() as u32;
+			if chunks[0] == HapiCompressionType::None {
+				out.extend_from_slice(contents);
+			} else {
+				let chunks: Vec<_> = contents
+					.chunks(HAPI_CHUNK_SIZE as usize)
+					.zip(chunks.iter().cycle())
+					.enumerate()
+					.map(|(i, (piece, &compression))| {
+						let mut chunk = chunk(piece, compression);
+						if *bad_checksum && i == 0 {
+							chunk[15] ^= 0xff;
+						}
+						chunk
+					})
+					.collect();
+				for chunk in &chunks {
+					out.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+				}
+				for chunk in &chunks {
+					out.extend_from_slice(chunk);
+				}
+			}
+
+			put_u32(&mut out, record, offset);
+			put_u32(&mut out, record + 4, contents.len() as u32);
+			out[record + 8] = chunks[0] as u8;
+		}
+
+		let stored = self
+			.stored_key
+			.unwrap_or_else(|| self.key.map_or(0, encode_key));
+		out[..4].copy_from_slice(b"HAPI");
+		out[4..8].copy_from_slice(HAPI_ARCHIVE_MARKER);
+		put_u32(&mut out, 8, toc_end);
+		put_u32(&mut out, 12, stored);
+		put_u32(&mut out, 16, HAPI_HEADER_SIZE as u32);
+
+		cipher(self.key, HAPI_HEADER_SIZE as u32, 0, &mut out);
+		out.extend_from_slice(&self.trailing);
+		out
+	}
+}
+
+// Writes the records of a directory holding `entries` (and everything under it) to the
+// end of `out`, returning where its own record is. Files are left to be filled in once
+// the table of contents is done, so they're added to `files` with where their record is
+fn write_dir<'n>(
+	entries: &'n [Node],
+	out: &mut Vec<u8>,
+	files: &mut Vec<(usize, &'n Node)>,
+) -> u32 {
+	let record = alloc(out, 8);
+	let index = alloc(out, 9 * entries.len());
+	put_u32(out, record, entries.len() as u32);
+	put_u32(out, record + 4, index as u32);
+
+	for (i, node) in entries.iter().enumerate() {
+		let (name, target, is_dir) = match node {
+			Node::File { name, .. } => {
+				let record = alloc(out, 9);
+				files.push((record, node));
+				(name, record as u32, false)
+			}
+			Node::Dir { name, entries } => (name, write_dir(entries, out, files), true),
+			Node::DirAt { name, offset } => (name, *offset, true),
+		};
+
+		let name_offset = out.len() as u32;
+		out.extend_from_slice(name);
+		out.push(0);
+
+		let entry = index + 9 * i;
+		put_u32(out, entry, name_offset);
+		put_u32(out, entry + 4, target);
+		out[entry + 8] = is_dir as u8;
+	}
+
+	record as u32
+}
+
+fn alloc(out: &mut Vec<u8>, len: usize) -> usize {
+	let start = out.len();
+	out.resize(start + len, 0);
+	start
+}
+
+fn put_u32(out: &mut [u8], pos: usize, value: u32) {
+	out[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// A compressed chunk holding `contents`, header and all
+pub(crate) fn chunk(contents: &[u8], compression: HapiCompressionType) -> Vec<u8> {
+	let data = match compression {
+		HapiCompressionType::None => unreachable!("chunks are always compressed"),
+		HapiCompressionType::Lz77 => lz77_literals(contents),
+		HapiCompressionType::Zlib => {
+			// stored blocks only: inflating back-references goes through rle-decode-fast,
+			// which trips the standard library's UB checks in debug builds
+			let options = zlib::EncodeOptions::new().no_compression();
+			let mut encoder = zlib::Encoder::with_options(Vec::new(), options).unwrap();
+			encoder.write_all(contents).unwrap();
+			encoder.finish().into_result().unwrap()
+		}
+	};
+	chunk_with_data(compression, contents.len() as u32, &data)
+}
+
+// A chunk with `data` as its (supposedly compressed) data, and a correct checksum
+pub(crate) fn chunk_with_data(
+	compression: HapiCompressionType,
+	decompressed_size: u32,
+	data: &[u8],
+) -> Vec<u8> {
+	let mut chunk = b"SQSH".to_vec();
+	chunk.extend_from_slice(&[2, compression as u8, 0]);
+	chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+	chunk.extend_from_slice(&decompressed_size.to_le_bytes());
+	chunk.extend_from_slice(&chunk_checksum(data).to_le_bytes());
+	chunk.extend_from_slice(data);
+	chunk
+}
+
+// Encodes `data` as LZ77 without any back-references: every byte is a literal, and a
+// pointer with offset 0 ends it
+fn lz77_literals(data: &[u8]) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut items = data.iter().map(Some).chain([None]).peekable();
+
+	while items.peek().is_some() {
+		let tag_pos = out.len();
+		let mut tag = 0;
+		out.push(0);
+		for (bit, item) in items.by_ref().take(8).enumerate() {
+			match item {
+				Some(&byte) => out.push(byte),
+				None => {
+					tag |= 1 << bit;
+					out.extend_from_slice(&[0, 0]);
+				}
+			}
+		}
+		out[tag_pos] = tag;
+	}
+
+	out
+}
+
+// Data that compresses well, but isn't all the same byte
+pub(crate) fn pattern(len: usize) -> Vec<u8> {
+	(0..len).map(|i| (i * 7 + (i >> 9)) as u8).collect()
+}
+
+// A few files of each kind (and size), some of them in subdirectories
+pub(crate) fn sample() -> Vec<Node> {
+	vec![
+		raw_file("Copyright.txt", "Hello HAPI\n"),
+		dir(
+			"gamedata",
+			vec![
+				file("SIDEDATA.TDF", "[SIDE0]\n{\nname=ARM;\n}\n".repeat(50)),
+				file("empty.tdf", ""),
+			],
+		),
+		dir(
+			"units",
+			vec![
+				mixed_file(
+					"ARMCOM.FBI",
+					"[UNITINFO]\n{\n}\n",
+					&[HapiCompressionType::Lz77],
+				),
+				dir("sub", vec![file("deep.txt", "deep")]),
+			],
+		),
+		dir("anims", vec![file("big.gaf", pattern(200_000))]),
+	]
+}
+
+pub(crate) fn archive_with_bad_checksum() -> Vec<u8> {
+	Fixture::new(vec![
+		Node::File {
+			name: b"bad.txt".to_vec(),
+			contents: b"the checksum doesn't match this".to_vec(),
+			chunks: vec![HapiCompressionType::Zlib],
+			bad_checksum: true,
+		},
+		file("good.txt", "this one is fine"),
+	])
+	.build()
+}
+
+// The archive ends partway through the last chunk of `cut.gaf`
+pub(crate) fn archive_with_truncated_chunk() -> Vec<u8> {
+	let mut archive = Fixture::new(vec![
+		file("whole.txt", "all here"),
+		file("cut.gaf", pattern(100_000)),
+	])
+	.build();
+	archive.truncate(archive.len() - 100);
+	archive
+}
+
+// The root directory contains a directory that is the root directory again
+pub(crate) fn archive_with_cyclic_entry() -> Vec<u8> {
+	Fixture::new(vec![
+		file("file.txt", "round and round"),
+		dir_at("loop", HAPI_HEADER_SIZE as u32),
+	])
+	.build()
+}
+
+pub(crate) fn archive_with_empty_name() -> Vec<u8> {
+	Fixture::new(vec![
+		file("", "no name"),
+		dir("sub", vec![file("", "no name either")]),
+	])
+	.build()
+}
+
+pub(crate) fn archive_with_dotdot_name() -> Vec<u8> {
+	Fixture::new(vec![
+		dir("..", vec![file("escaped.txt", "out")]),
+		dir("sub", vec![file("..", "up from sub")]),
+	])
+	.build()
+}
+
+// An LZ77 chunk whose data ends right after a tag saying a pointer comes next
+pub(crate) fn truncated_lz77_chunk() -> Vec<u8> {
+	chunk_with_data(HapiCompressionType::Lz77, 16, &[0b10, b'a'])
+}
+
+// A directory under the system's temporary directory, removed along with everything in
+// it when dropped
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+	pub(crate) fn new() -> Self {
+		static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+		let path = std::env::temp_dir().join(format!(
+			"hapi-test-{}-{}",
+			std::process::id(),
+			COUNT.fetch_add(1, Ordering::Relaxed)
+		));
+		let _ = fs::remove_dir_all(&path);
+		fs::create_dir_all(&path).unwrap();
+		TempDir(path)
+	}
+
+	pub(crate) fn path(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+// Every file under `dir`, as paths relative to it, sorted
+pub(crate) fn files_under(dir: &Path) -> Vec<PathBuf> {
+	fn walk(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) {
+		for entry in fs::read_dir(dir).unwrap() {
+			let path = entry.unwrap().path();
+			if path.is_dir() {
+				walk(root, &path, files);
+			} else {
+				files.push(path.strip_prefix(root).unwrap().to_path_buf());
+			}
+		}
+	}
+
+	let mut files = Vec::new();
+	walk(dir, dir, &mut files);
+	files.sort();
+	files
+}
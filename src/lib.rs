@@ -6,5 +6,10 @@ pub use hapi::*;
 
 pub mod prelude {
 	#[doc(no_inline)]
-	pub use crate::{HapiArchive, HapiCompressionType, HapiDirectory, HapiEntry, HapiFile};
+	pub use crate::{
+		BufferPool, ChunkInfo, ConflictPolicy, EntryKind, ExtractOptions, ExtractSink, FsSink,
+		HapiArchive, HapiCompressionType, HapiDirectory, HapiEntry, HapiError, HapiFile,
+		HapiFileReader, HapiKind, HapiWarning, Manifest, OpenOptions, OpenTiming, ReadSeek,
+		SmallTocPolicy, SyncOptions, UnknownMarkerPolicy, ZlibBackend,
+	};
 }
@@ -2,62 +2,182 @@ use hapi::prelude::*;
 use std::env;
 use std::error::Error;
 use std::fs::File;
+use std::path::Path;
+use std::process;
 
-fn main() -> Result<(), Box<dyn Error>> {
-	let filename = if let Some(s) = env::args().nth(1) {
-		s
-	} else {
-		"Example.ufo".to_string()
+const USAGE: &str = "\
+Usage:
+	hapi list <archive> [--tree]
+	hapi extract <archive> [dest] [--filter <glob>]
+	hapi info <archive>
+	hapi verify <archive>";
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+
+	let result = match args.first().map(String::as_str) {
+		Some("list") => list(&args[1..]),
+		Some("extract") => extract(&args[1..]),
+		Some("info") => archive_arg(&args).and_then(info),
+		Some("verify") => archive_arg(&args).and_then(verify),
+		Some("help" | "-h" | "--help") => {
+			println!("{}", USAGE);
+			Ok(())
+		}
+		_ => Err(USAGE.into()),
 	};
-	let archive = File::open(filename)?;
-	// let file = BufReader::new(file);
-	// let mut file = HapiReader::new(file)?;
-	// let mut contents = Vec::new();
-	// file.read_to_end(&mut contents);
-	// std::io::stdout().write_all(&contents);
-	let archive = HapiArchive::open(archive)?;
 
-	extract_a_file(&archive)?;
+	if let Err(e) = result {
+		eprintln!("{}", e);
+		process::exit(1);
+	}
+}
 
-	// archive.extract_all("./testout")?;
+// Subcommands that take nothing but an archive path
+fn archive_arg(args: &[String]) -> Result<&str, Box<dyn Error>> {
+	match args {
+		[_, archive] => Ok(archive),
+		_ => Err(USAGE.into()),
+	}
+}
 
-	// list_files(&archive.contents);
+fn open(path: &str) -> Result<HapiArchive<File>, Box<dyn Error>> {
+	let archive = HapiArchive::open(File::open(path)?)?;
+	for warning in archive.warnings() {
+		eprintln!("Warning: {}", warning);
+	}
+	Ok(archive)
+}
 
-	//eprintln!("{:#x?}", file);
+fn list(args: &[String]) -> Result<(), Box<dyn Error>> {
+	let (path, tree) = match args {
+		[path] => (path, false),
+		[path, flag] | [flag, path] if flag == "--tree" => (path, true),
+		_ => return Err(USAGE.into()),
+	};
+	let archive = open(path)?;
 
+	if tree {
+		print!("{}", archive.tree_string());
+	} else {
+		list_files(&archive.root_dir);
+	}
 	Ok(())
 }
 
 fn list_files(dir: &HapiDirectory) {
-	println!("{}", dir.path_str());
-
 	for entry in dir {
 		match entry {
-			HapiEntry::File(file) => println!("{}", file.path_str()),
+			HapiEntry::File(file) => println!("{}", display_path(file.path())),
 			HapiEntry::Directory(dir) => list_files(dir),
 		}
 	}
 }
 
-fn extract_a_file(archive: &HapiArchive<File>) -> Result<(), Box<dyn Error>> {
-	let file = archive
-		.contents()
-		.find_map(find_file)
-		.expect("didn't find the file");
+fn extract(args: &[String]) -> Result<(), Box<dyn Error>> {
+	let mut positional = Vec::new();
+	let mut filter = None;
+
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--filter" => filter = Some(args.next().ok_or(USAGE)?.as_str()),
+			_ => positional.push(arg.as_str()),
+		}
+	}
+
+	let (path, dest) = match positional[..] {
+		[path] => (path, Path::new(".")),
+		[path, dest] => (path, Path::new(dest)),
+		_ => return Err(USAGE.into()),
+	};
+	let archive = open(path)?;
+
+	match filter {
+		None => archive.extract_all(dest)?,
+		Some(pattern) => extract_matching(&archive, dest, pattern)?,
+	}
+	Ok(())
+}
+
+// Extracts only the files whose path within the archive matches `pattern`, keeping
+// their directory structure. The library hands us each path already sanitized, so
+// names like `..` can't take a file outside of `dest`
+fn extract_matching(
+	archive: &HapiArchive<File>,
+	dest: &Path,
+	pattern: &str,
+) -> Result<(), Box<dyn Error>> {
+	archive.extract_with_mapping(|path| {
+		glob_match(pattern, &display_path(path)).then(|| dest.join(path))
+	})?;
+	Ok(())
+}
+
+fn info(path: &str) -> Result<(), Box<dyn Error>> {
+	let archive = open(path)?;
+
+	println!("Archive:           {}", path);
+	println!("Files:             {}", archive.count_files());
+	println!("Directories:       {}", archive.count_dirs());
+	println!(
+		"Uncompressed size: {} bytes",
+		archive.total_uncompressed_size()
+	);
+	Ok(())
+}
+
+fn verify(path: &str) -> Result<(), Box<dyn Error>> {
+	let archive = open(path)?;
+
+	if archive.is_valid()? {
+		println!("{}: OK", path);
+		Ok(())
+	} else {
+		Err(format!("{}: archive is corrupt", path).into())
+	}
+}
 
-	archive.write_file(file, &mut std::io::stdout())
+// Entry paths are rooted at `.`; drop that for display and matching
+fn display_path(path: &Path) -> String {
+	let path = path.strip_prefix(".").unwrap_or(path);
+	path.to_string_lossy().replace('\\', "/")
 }
 
-fn find_file(ent: &HapiEntry) -> Option<&HapiFile> {
-	match ent {
-		HapiEntry::File(f) => {
-			if f.path_str() == "./gamedata/SIDEDATA.TDF" {
-				eprintln!("{}", f.path_str());
-				Some(f)
-			} else {
-				None
+// Matches `text` against a glob `pattern` supporting `*` (which doesn't cross `/`) and `?`,
+// ignoring case since TA's file names are from a case-insensitive world
+fn glob_match(pattern: &str, text: &str) -> bool {
+	let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+	let text: Vec<char> = text.to_lowercase().chars().collect();
+
+	// position to resume from after the most recent `*`, as (pattern, text)
+	let mut backtrack = None;
+	let (mut p, mut t) = (0, 0);
+
+	while t < text.len() {
+		match pattern.get(p) {
+			Some('*') => {
+				backtrack = Some((p + 1, t));
+				p += 1;
+			}
+			Some('?') if text[t] != '/' => {
+				p += 1;
+				t += 1;
 			}
+			Some(&c) if c == text[t] => {
+				p += 1;
+				t += 1;
+			}
+			_ => match backtrack {
+				Some((bp, bt)) if text[bt] != '/' => {
+					backtrack = Some((bp, bt + 1));
+					p = bp;
+					t = bt + 1;
+				}
+				_ => return false,
+			},
 		}
-		HapiEntry::Directory(d) => d.into_iter().find_map(find_file),
 	}
+
+	pattern[p..].iter().all(|&c| c == '*')
 }